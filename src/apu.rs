@@ -0,0 +1,838 @@
+//
+//      Audio Processing Unit
+//
+
+/*
+  FF10-FF14   Channel 1, square wave with frequency sweep
+  FF16-FF19   Channel 2, square wave
+  FF1A-FF1E   Channel 3, wave from Wave RAM
+  FF20-FF23   Channel 4, noise
+  FF24        NR50, master volume
+  FF25        NR51, panning
+  FF26        NR52, power and channel status
+  FF30-FF3F   Wave RAM, 32 4-bit samples
+*/
+
+use std::io;
+
+use state::{StateWriter, StateReader};
+
+// T-cycles per second
+pub const CLOCK_SPEED: u32 = 4194304;
+// Default output sample rate
+pub const SAMPLE_RATE: u32 = 44100;
+
+// The frame sequencer runs at 512Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CLOCK_SPEED / 512;
+
+// Waveforms for the 12.5%, 25%, 50% and 75% duty cycles
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+// Bits that always read as 1 in FF10-FF26
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,   // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,   // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,   // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,   // NR40-NR44
+    0x00, 0x00, 0x70,               // NR50-NR52
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Turns a channel off after a number of frame sequencer length clocks
+#[derive(Default)]
+struct Length {
+    enabled: bool,
+    counter: u16,
+    max: u16,       // 64, or 256 for the wave channel
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length { enabled: false, counter: 0, max: max }
+    }
+
+    fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 { self.counter = self.max }
+    }
+
+    // Returns false when the channel should be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = try!(r.bool());
+        self.counter = try!(r.u16());
+        Ok(())
+    }
+}
+
+// Volume envelope, NRx2
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    // The channel's DAC is off when the upper 5 bits of NRx2 are all 0
+    fn is_dac_on(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 { return }
+
+        if self.timer > 0 { self.timer -= 1 }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.volume = try!(r.u8());
+        self.timer = try!(r.u8());
+        Ok(())
+    }
+}
+
+// Channels 1 and 2
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,      // 11 bits
+    timer: u32,
+
+    length: Length,
+    envelope: Envelope,
+
+    // Frequency sweep, channel 1 only
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_freq: u16,
+}
+
+impl Square {
+    fn new() -> Square {
+        Square { length: Length::new(64), ..Default::default() }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.is_dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.shadow_freq = self.freq;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_calc();
+        }
+    }
+
+    // Next sweep frequency. Turns the channel off if it overflows.
+    fn sweep_calc(&mut self) -> u16 {
+        let delta = self.shadow_freq >> self.sweep_shift;
+        let freq = if self.sweep_negate {
+            self.shadow_freq - delta
+        } else {
+            self.shadow_freq + delta
+        };
+        if freq > 2047 { self.enabled = false }
+        freq
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 { self.sweep_timer -= 1 }
+        if self.sweep_timer != 0 { return }
+
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let freq = self.sweep_calc();
+            if freq <= 2047 && self.sweep_shift != 0 {
+                self.shadow_freq = freq;
+                self.freq = freq;
+                self.sweep_calc();
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() { self.enabled = false }
+    }
+
+    fn step(&mut self) {
+        if self.timer > 0 { self.timer -= 1 }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) & 7;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && DUTY_TABLE[self.duty as usize][self.duty_pos as usize] != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty_pos);
+        w.u16(self.freq);
+        w.u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.sweep_timer);
+        w.bool(self.sweep_enabled);
+        w.u16(self.shadow_freq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = try!(r.bool());
+        self.duty_pos = try!(r.u8());
+        self.freq = try!(r.u16());
+        self.timer = try!(r.u32());
+        try!(self.length.load_state(r));
+        try!(self.envelope.load_state(r));
+        self.sweep_timer = try!(r.u8());
+        self.sweep_enabled = try!(r.bool());
+        self.shadow_freq = try!(r.u16());
+        Ok(())
+    }
+}
+
+// Channel 3
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_on: bool,
+    volume_shift: u8,   // 4 (mute), 0 (100%), 1 (50%) or 2 (25%)
+    freq: u16,
+    timer: u32,
+    position: u8,       // 0-31, which 4-bit sample is playing
+    sample: u8,
+
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave { length: Length::new(256), volume_shift: 4, ..Default::default() }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_on;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() { self.enabled = false }
+    }
+
+    fn step(&mut self) {
+        if self.timer > 0 { self.timer -= 1 }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 31;
+
+            // High nibble first
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled { self.sample >> self.volume_shift } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.freq);
+        w.u32(self.timer);
+        w.u8(self.position);
+        w.u8(self.sample);
+        self.length.save_state(w);
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = try!(r.bool());
+        self.freq = try!(r.u16());
+        self.timer = try!(r.u32());
+        self.position = try!(r.u8());
+        self.sample = try!(r.u8());
+        try!(self.length.load_state(r));
+        r.bytes(&mut self.ram)
+    }
+}
+
+// Channel 4
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    clock_shift: u8,
+    width_mode: bool,   // 7 bit LFSR instead of 15 bits
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise { length: Length::new(64), lfsr: 0x7FFF, ..Default::default() }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.is_dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() { self.enabled = false }
+    }
+
+    fn step(&mut self) {
+        if self.timer > 0 { self.timer -= 1 }
+        if self.timer == 0 {
+            self.timer = self.period();
+            // Shifts of 14 and 15 don't clock the LFSR at all
+            if self.clock_shift >= 14 { return }
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 { self.envelope.volume } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u32(self.timer);
+        w.u16(self.lfsr);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = try!(r.bool());
+        self.timer = try!(r.u32());
+        self.lfsr = try!(r.u16());
+        try!(self.length.load_state(r));
+        self.envelope.load_state(r)
+    }
+}
+
+pub struct Apu {
+    power: bool,
+    // Last values written to FF10-FF26, for reads and save states
+    regs: [u8; 0x17],
+
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+
+    frame_seq_timer: u32,
+    frame_seq_step: u8,
+
+    // Resampling. Every T-cycle's output is summed up, and the average of the
+    // cycles in a sample period becomes the sample.
+    sample_rate: u32,
+    sample_counter: u32,
+    acc: [f32; 2],
+    acc_cycles: u32,
+    // High pass filter, removes the DC offset of the DACs like the capacitor
+    // on the real thing
+    hp_charge: [f32; 2],
+    hp_factor: f32,
+
+    // Interleaved stereo samples, left first
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            // On after the BIOS
+            power: true,
+            regs: [0; 0x17],
+
+            ch1: Square::new(),
+            ch2: Square::new(),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+
+            frame_seq_timer: 0,
+            frame_seq_step: 0,
+
+            sample_rate: 0,
+            sample_counter: 0,
+            acc: [0.0; 2],
+            acc_cycles: 0,
+            hp_charge: [0.0; 2],
+            hp_factor: 0.0,
+
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(SAMPLE_RATE);
+        apu
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.hp_factor = 0.999958f32.powf(CLOCK_SPEED as f32 / rate as f32);
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Samples produced since the last clear_samples(), interleaved stereo
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn rb(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                0x70 | ((self.power as u8) << 7) |
+                ((self.ch4.enabled as u8) << 3) |
+                ((self.ch3.enabled as u8) << 2) |
+                ((self.ch2.enabled as u8) << 1) |
+                ((self.ch1.enabled as u8) << 0)
+            }
+            0xFF10 ... 0xFF25 => {
+                let i = (addr - 0xFF10) as usize;
+                self.regs[i] | READ_MASKS[i]
+            }
+            0xFF30 ... 0xFF3F => self.ch3.ram[(addr - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF26 => self.set_power(val & 0x80 != 0),
+            0xFF30 ... 0xFF3F => self.ch3.ram[(addr - 0xFF30) as usize] = val,
+            0xFF10 ... 0xFF25 => {
+                // Registers can't be written while powered off
+                if !self.power { return }
+                self.regs[(addr - 0xFF10) as usize] = val;
+                self.write_reg(addr, val);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_reg(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF10 => {
+                self.ch1.sweep_period = (val >> 4) & 0x07;
+                self.ch1.sweep_negate = val & 0x08 != 0;
+                self.ch1.sweep_shift = val & 0x07;
+            }
+            0xFF11 => write_duty_length(&mut self.ch1, val),
+            0xFF12 => write_envelope(&mut self.ch1, val),
+            0xFF13 => self.ch1.freq = (self.ch1.freq & 0x700) | val as u16,
+            0xFF14 => {
+                write_freq_high(&mut self.ch1.freq, &mut self.ch1.length, val);
+                if val & 0x80 != 0 { self.ch1.trigger() }
+            }
+
+            0xFF16 => write_duty_length(&mut self.ch2, val),
+            0xFF17 => write_envelope(&mut self.ch2, val),
+            0xFF18 => self.ch2.freq = (self.ch2.freq & 0x700) | val as u16,
+            0xFF19 => {
+                write_freq_high(&mut self.ch2.freq, &mut self.ch2.length, val);
+                if val & 0x80 != 0 { self.ch2.trigger() }
+            }
+
+            0xFF1A => {
+                self.ch3.dac_on = val & 0x80 != 0;
+                if !self.ch3.dac_on { self.ch3.enabled = false }
+            }
+            0xFF1B => self.ch3.length.load(val),
+            0xFF1C => {
+                self.ch3.volume_shift = match (val >> 5) & 0x03 {
+                    0 => 4,
+                    code => code - 1,
+                };
+            }
+            0xFF1D => self.ch3.freq = (self.ch3.freq & 0x700) | val as u16,
+            0xFF1E => {
+                write_freq_high(&mut self.ch3.freq, &mut self.ch3.length, val);
+                if val & 0x80 != 0 { self.ch3.trigger() }
+            }
+
+            0xFF20 => self.ch4.length.load(val & 0x3F),
+            0xFF21 => {
+                self.ch4.envelope.write(val);
+                if !self.ch4.envelope.is_dac_on() { self.ch4.enabled = false }
+            }
+            0xFF22 => {
+                self.ch4.clock_shift = val >> 4;
+                self.ch4.width_mode = val & 0x08 != 0;
+                self.ch4.divisor_code = val & 0x07;
+            }
+            0xFF23 => {
+                self.ch4.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 { self.ch4.trigger() }
+            }
+
+            // NR50 and NR51 are only used when mixing, straight from regs
+            _ => {}
+        }
+    }
+
+    // Turning the APU off clears every register but Wave RAM
+    fn set_power(&mut self, on: bool) {
+        if self.power == on { return }
+        self.power = on;
+
+        if !on {
+            let ram = self.ch3.ram;
+            self.regs = [0; 0x17];
+            self.ch1 = Square::new();
+            self.ch2 = Square::new();
+            self.ch3 = Wave::new();
+            self.ch3.ram = ram;
+            self.ch4 = Noise::new();
+        } else {
+            self.frame_seq_step = 0;
+        }
+    }
+
+    // Step the APU a number of T-cycles forward
+    pub fn step(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            if self.power {
+                self.frame_seq_timer += 1;
+                if self.frame_seq_timer >= FRAME_SEQUENCER_PERIOD {
+                    self.frame_seq_timer = 0;
+                    self.clock_frame_sequencer();
+                }
+
+                self.ch1.step();
+                self.ch2.step();
+                self.ch3.step();
+                self.ch4.step();
+            }
+
+            self.mix();
+        }
+    }
+
+    // Step   Length  Sweep  Envelope
+    // 0      Clock   -      -
+    // 1      -       -      -
+    // 2      Clock   Clock  -
+    // 3      -       -      -
+    // 4      Clock   -      -
+    // 5      -       -      -
+    // 6      Clock   Clock  -
+    // 7      -       -      Clock
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_seq_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => {}
+        }
+        self.frame_seq_step = (self.frame_seq_step + 1) & 7;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    // Adds this T-cycle's output to the current sample, and finishes the
+    // sample if its period is over
+    fn mix(&mut self) {
+        let outputs = [
+            dac(self.ch1.envelope.is_dac_on(), self.ch1.output()),
+            dac(self.ch2.envelope.is_dac_on(), self.ch2.output()),
+            dac(self.ch3.dac_on, self.ch3.output()),
+            dac(self.ch4.envelope.is_dac_on(), self.ch4.output()),
+        ];
+
+        // NR51: bits 4-7 send channels 1-4 to the left, bits 0-3 to the right
+        let panning = self.regs[0x15];
+        for (i, &out) in outputs.iter().enumerate() {
+            if panning & (0x10 << i) != 0 { self.acc[0] += out }
+            if panning & (0x01 << i) != 0 { self.acc[1] += out }
+        }
+        self.acc_cycles += 1;
+
+        self.sample_counter += self.sample_rate;
+        if self.sample_counter < CLOCK_SPEED { return }
+        self.sample_counter -= CLOCK_SPEED;
+
+        // NR50: left volume in bits 4-6, right in bits 0-2
+        let volumes = [(self.regs[0x14] >> 4) & 0x07, self.regs[0x14] & 0x07];
+        for side in 0..2 {
+            let mut out = self.acc[side] / self.acc_cycles as f32;
+            out *= (volumes[side] + 1) as f32 / 8.0 / 4.0;
+
+            // High pass filter
+            let filtered = out - self.hp_charge[side];
+            self.hp_charge[side] = out - filtered * self.hp_factor;
+
+            let sample = (filtered * i16::max_value() as f32)
+                .max(i16::min_value() as f32)
+                .min(i16::max_value() as f32);
+            self.samples.push(sample as i16);
+            self.acc[side] = 0.0;
+        }
+        self.acc_cycles = 0;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.power);
+        w.bytes(&self.regs);
+        w.u32(self.frame_seq_timer);
+        w.u8(self.frame_seq_step);
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.power = try!(r.bool());
+        try!(r.bytes(&mut self.regs));
+
+        // Restore the register settings first, the internal counters that
+        // follow overwrite what they changed
+        self.ch1 = Square::new();
+        self.ch2 = Square::new();
+        self.ch3 = Wave::new();
+        self.ch4 = Noise::new();
+        let regs = self.regs;
+        for (i, &val) in regs.iter().enumerate() {
+            let addr = 0xFF10 + i as u16;
+            match addr {
+                // Without triggering the channels
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => self.write_reg(addr, val & 0x7F),
+                _ => self.write_reg(addr, val),
+            }
+        }
+
+        self.frame_seq_timer = try!(r.u32());
+        self.frame_seq_step = try!(r.u8());
+        try!(self.ch1.load_state(r));
+        try!(self.ch2.load_state(r));
+        try!(self.ch3.load_state(r));
+        self.ch4.load_state(r)
+    }
+}
+
+fn write_duty_length(ch: &mut Square, val: u8) {
+    ch.duty = val >> 6;
+    ch.length.load(val & 0x3F);
+}
+
+fn write_envelope(ch: &mut Square, val: u8) {
+    ch.envelope.write(val);
+    if !ch.envelope.is_dac_on() { ch.enabled = false }
+}
+
+// NRx4, the top 3 bits of the frequency and the length enable
+fn write_freq_high(freq: &mut u16, length: &mut Length, val: u8) {
+    *freq = (*freq & 0xFF) | ((val as u16 & 0x07) << 8);
+    length.enabled = val & 0x40 != 0;
+}
+
+// Converts a channel's 4-bit output to -1.0-1.0. DACs that are off output 0.
+fn dac(on: bool, val: u8) -> f32 {
+    if on { 1.0 - val as f32 / 7.5 } else { 0.0 }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod apu_tests {
+    use super::*;
+
+    // Runs the frame sequencer for a number of 512Hz steps
+    fn run_steps(apu: &mut Apu, steps: u32) {
+        apu.step(steps * FRAME_SEQUENCER_PERIOD);
+    }
+
+    #[test]
+    fn length_turns_channel_off() {
+        let mut apu = Apu::new();
+        apu.wb(0xFF25, 0xFF);
+        apu.wb(0xFF17, 0xF0);   // Full volume, DAC on
+        apu.wb(0xFF16, 0x3E);   // Length 2
+        apu.wb(0xFF19, 0xC0);   // Trigger with length enabled
+        assert_eq!(apu.rb(0xFF26) & 0x02, 0x02);
+
+        // Length is clocked at every other step
+        run_steps(&mut apu, 2);
+        assert_eq!(apu.rb(0xFF26) & 0x02, 0x02);
+        run_steps(&mut apu, 2);
+        assert_eq!(apu.rb(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let mut apu = Apu::new();
+        apu.wb(0xFF1A, 0x80);
+        apu.wb(0xFF1E, 0x80);
+        assert_eq!(apu.rb(0xFF26) & 0x04, 0x04);
+        apu.wb(0xFF1A, 0x00);
+        assert_eq!(apu.rb(0xFF26) & 0x04, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow() {
+        let mut apu = Apu::new();
+        apu.wb(0xFF12, 0xF0);
+        apu.wb(0xFF10, 0x11);   // Period 1, add, shift 1
+        apu.wb(0xFF13, 0x00);
+        apu.wb(0xFF14, 0x85);   // Frequency 0x500, trigger
+        assert_eq!(apu.rb(0xFF26) & 0x01, 0x01);
+
+        // The first sweep clock sets 0x780, and the overflow check right after
+        // it sees 0x780 + 0x3C0
+        run_steps(&mut apu, 2);
+        assert_eq!(apu.rb(0xFF26) & 0x01, 0x01);
+        run_steps(&mut apu, 1);
+        assert_eq!(apu.rb(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new();
+        apu.wb(0xFF24, 0x77);
+        apu.wb(0xFF30, 0x12);
+        apu.wb(0xFF26, 0x00);
+        assert_eq!(apu.rb(0xFF24), 0x00);
+        assert_eq!(apu.rb(0xFF26), 0x70);
+
+        // Ignored while off, except Wave RAM
+        apu.wb(0xFF24, 0x77);
+        assert_eq!(apu.rb(0xFF24), 0x00);
+        assert_eq!(apu.rb(0xFF30), 0x12);
+
+        apu.wb(0xFF26, 0x80);
+        apu.wb(0xFF24, 0x77);
+        assert_eq!(apu.rb(0xFF24), 0x77);
+        assert_eq!(apu.rb(0xFF11), 0x3F);
+    }
+
+    #[test]
+    fn produces_samples() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48000);
+        apu.wb(0xFF24, 0x77);
+        apu.wb(0xFF25, 0x22);
+        apu.wb(0xFF17, 0xF0);
+        apu.wb(0xFF16, 0x80);
+        apu.wb(0xFF18, 0x00);
+        apu.wb(0xFF19, 0x87);
+
+        // One second
+        apu.step(CLOCK_SPEED);
+        assert_eq!(apu.samples().len(), 48000 * 2);
+        assert!(apu.samples().iter().any(|&s| s > 1000));
+        assert!(apu.samples().iter().any(|&s| s < -1000));
+
+        apu.clear_samples();
+        assert!(apu.samples().is_empty());
+    }
+}
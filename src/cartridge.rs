@@ -1,6 +1,5 @@
 
 use std::fmt;
-use emulator::Emulator;
 
 #[derive(Default)]
 #[repr(C, packed)]
@@ -13,13 +12,12 @@ pub struct CartridgeHeader {
     // TODO: Don't be lazy and implement our own Default trait
     nintendo_logo: [u16; 24],
 
-    // Game title in upper case ASCII
-    game_title: [u8; 16],
-    //manufacturer_code: [u8; 4],
-
+    // Game title in upper case ASCII. In CGB cartridges, the last byte is the
+    // CGB flag instead:
     //80h - Game supports CGB functions, but works on old gameboys also.
     //C0h - Game works on CGB only (physically the same as 80h).
-    //cgb_flag: u8,
+    game_title: [u8; 16],
+    //manufacturer_code: [u8; 4],
 
     // Used by newer games
     new_licence_code: [u8; 2],
@@ -43,7 +41,9 @@ pub struct CartridgeHeader {
     // 01h - 2 KBytes
     // 02h - 8 Kbytes
     // 03h - 32 KBytes (4 banks of 8KBytes each)
-    ram_size: u8,
+    // 04h - 128 KBytes (16 banks of 8KBytes each)
+    // 05h - 64 KBytes (8 banks of 8KBytes each)
+    pub ram_size: u8,
 
     // 0 = Japanese, 1 = Non-Japanese
     dest_code: u8,
@@ -58,13 +58,32 @@ pub struct CartridgeHeader {
 }
 
 impl CartridgeHeader {
+    // Whether the cartridge has battery backed RAM or RTC, which should be kept
+    // in a save file between runs
+    pub fn has_battery(&self) -> bool {
+        match self.cartridge_type {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF => true,
+            _ => false,
+        }
+    }
+
+    // Whether the game supports the CGB
+    pub fn is_cgb(&self) -> bool {
+        self.game_title[15] & 0x80 != 0
+    }
+
+    pub fn get_global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
+
     pub fn get_game_title(&self) -> String {
         use std::str;
         use std::env;
         
         let args: Vec<_> = env::args().collect();
+        let title_len = if self.is_cgb() {15} else {16};
         let mut title = String::from(
-            match str::from_utf8(&self.game_title) {
+            match str::from_utf8(&self.game_title[..title_len]) {
                 Ok(val) => val,
                 Err(err) => {
                     warn!("Couldn't read rom name from header, using file name instead");
@@ -103,8 +122,10 @@ impl fmt::Debug for CartridgeHeader {
     }
 }
 
+// The header ends here, read_header_impl() needs ROMs to be at least this long
+pub const HEADER_END: usize = 0x150;
 
-pub fn read_header_impl(emu: &Emulator) -> CartridgeHeader {
+pub fn read_header_impl(rom: &[u8]) -> CartridgeHeader {
     use std::slice;
     use std::io::Read;
 
@@ -114,7 +135,7 @@ pub fn read_header_impl(emu: &Emulator) -> CartridgeHeader {
     let mut buffer: [u8; HEADER_SIZE] = [0u8; HEADER_SIZE];
 
     for i in 0..HEADER_SIZE {
-        buffer[i] = emu.mem.rom_loaded[i + HEADER_OFFSET];
+        buffer[i] = rom[i + HEADER_OFFSET];
     }
 
     let mut buffer_slice: &[u8] = &buffer;
@@ -144,4 +165,16 @@ mod cart_tests {
 
         assert_eq!(0x50, mem::size_of::<CartridgeHeader>());
     }
+
+    #[test]
+    fn cgb_flag() {
+        let mut rom = vec![0u8; 0x150];
+        rom[0x134..0x138].copy_from_slice(b"GAME");
+        assert!(!read_header_impl(&rom).is_cgb());
+
+        rom[0x143] = 0x80;
+        let header = read_header_impl(&rom);
+        assert!(header.is_cgb());
+        assert_eq!(header.get_game_title(), "GAME");
+    }
 }
\ No newline at end of file
@@ -19,20 +19,20 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         1 }) );
 
     macro_rules! ld_n (
-        ($reg1:ident) => ({ r.$reg1 = m.rb(r.bump());
+        ($reg1:ident) => ({ r.$reg1 = m.rb_cycle(r.bump());
         2 }) );
 
     macro_rules! ld_nn (
         ($reg1:ident, $reg2:ident) => ({
-            r.$reg2 = m.rb(r.bump());
-            r.$reg1 = m.rb(r.bump());
+            r.$reg2 = m.rb_cycle(r.bump());
+            r.$reg1 = m.rb_cycle(r.bump());
         3 }) );
 
     macro_rules! call (
         () => ({
-            r.sp = r.sp.wrapping_sub(2);
-            m.ww(r.sp, r.pc + 2);
-            let target = m.rw(r.pc);
+            let target = m.rw_cycles(r.pc);
+            let ret = r.pc + 2;
+            r.push(m, ret);
             debug!("CALL to {:04X}", target);
             r.pc = target;
         6 }) );
@@ -42,12 +42,12 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         3 }) );
 
     macro_rules! ret_if (
-        ($should_ret:expr) => (if $should_ret {r.ret(m); 5} else {
+        ($should_ret:expr) => (if $should_ret {m.tick(); r.ret(m); 5} else {
         2 }) );
 
     macro_rules! jp (
         () => ({
-            let j_addr = m.rw(r.pc);
+            let j_addr = m.rw_cycles(r.pc);
             r.pc = j_addr;
         4 }) );
 
@@ -57,7 +57,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! jr (
         () => ({
-            let target = add_signed(r.pc, m.rb(r.bump())) + 1;
+            let target = add_signed(r.pc, m.rb_cycle(r.bump())) + 1;
             //debug!("JUMP(REL) to {:04X}", target);
             r.pc = target;
         3 }) );
@@ -96,8 +96,8 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! rst (
     ($e:expr) => ({
-        r.sp = r.sp.wrapping_sub(2);
-        m.ww(r.sp, r.pc);
+        let pc = r.pc;
+        r.push(m, pc);
         r.pc = $e;
     4 }) );
 
@@ -106,14 +106,14 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.a ^= $val;
         r.f.reset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! or_a (
     ($val:expr) => ({
         r.a |= $val;
         r.f.reset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! and_a (
     ($val:expr) => ({
@@ -122,7 +122,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.f.h.set();
         r.f.c.unset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! cp_a (
     ($val:expr) => ({
@@ -132,7 +132,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         if r.a < v {r.f.c.set()} else {r.f.c.unset()};
         r.f.h.set_if((r.a & 0xF) < (v & 0xF));
         //debug!("{:02X} & 0xF < ({:2X} & 0xF)    c:{:?} h:{:?} ", r.a, v, r.f.c.get(),r.f.h.get());
-    4 }) );
+    1 }) );
 
     macro_rules! rl( ($reg:expr, $cy:expr) => ({
         let ci = if r.f.c.get() {1} else {0};
@@ -157,14 +157,14 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.f.reset();
         r.$reg = r.$reg.rotate_left($n);
         r.f.c.set_if(r.$reg & 0x1 == 1);
-    4 }) );
+    1 }) );
 
     macro_rules! rrc (
     ($reg:ident, $n:expr) => ({
         r.f.reset();
         r.$reg = r.$reg.rotate_right($n);
         r.f.c.set_if(r.$reg & 0x80 != 0);
-    4 }) );
+    1 }) );
 
     macro_rules! add_hl(
     ($reg:expr) => ({
@@ -180,8 +180,8 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! push (
     ($reg:ident) => ({
-        r.sp = r.sp.wrapping_sub(2);
-        m.ww(r.sp, r.$reg());
+        let val = r.$reg();
+        r.push(m, val);
     4 }) );
 
     macro_rules! add_a (
@@ -237,7 +237,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
     macro_rules! ld_hlspn (
     () => ({
         r.f.reset();
-        let b = m.rb(r.bump()) as i8 as i16 as u16;
+        let b = m.rb_cycle(r.bump()) as i8 as i16 as u16;
         let res = b.wrapping_add(r.sp);
         r.h = (res >> 8) as u8;
         r.l = res as u8;
@@ -248,8 +248,8 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! ld_aIOn (
     () => ({
-        let b = m.rb(r.bump()) as u16;
-        r.a = m.rb(0xff00 | b);
+        let b = m.rb_cycle(r.bump()) as u16;
+        r.a = m.rb_cycle(0xff00 | b);
     3 }) );
 
     // TODO: use set_or_else for everything
@@ -304,15 +304,15 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x00 => 1,                                                  // nop
         0x01 => ld_nn!(b, c),                                       // ld_bcnn
 
-        0x02 => { m.wb(r.bc(), r.a); 2 }                            // ld_bca
+        0x02 => { m.wb_cycle(r.bc(), r.a); 2 }                      // ld_bca
         0x03 => inc_16!(b, c),                                      // inc_bc
         0x04 => inc!(b),                                            // inc_b
         0x05 => dec!(b),                                            // dec_b
         0x06 => ld_n!(b),                                           // ld_bn
         0x07 => rlc!(a, 1),                                         // rlca
-        0x08 => { let a = m.rw(r.pc); m.ww(a, r.sp); r.pc += 2; 5 } // ld_nnsp
+        0x08 => { let a = m.rw_cycles(r.pc); m.ww_cycles(a, r.sp); r.pc += 2; 5 } // ld_nnsp
         0x09 => add_hl!(r.bc()),                                    // add_hlbc
-        0x0a => { r.a = m.rb(r.bc()); 2 }                           // ld_abc
+        0x0a => { r.a = m.rb_cycle(r.bc()); 2 }                     // ld_abc
         0x0b => dec_16!(b, c),                                      // dec_bc
         0x0c => inc!(c),                                            // inc_c
         0x0d => dec!(c),                                            // dec_c
@@ -321,7 +321,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
         0x10 => { r.stop = true; 1}                                 // stop
         0x11 => ld_nn!(d, e),                                       // ld_denn
-        0x12 => { m.wb(r.de(), r.a); 2 }                            // ld_dea
+        0x12 => { m.wb_cycle(r.de(), r.a); 2 }                      // ld_dea
         0x13 => inc_16!(d, e),                                      // inc_de
         0x14 => inc!(d),                                            // inc_d
         0x15 => dec!(d),                                            // dec_d
@@ -329,7 +329,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x17 => rl!(r.a, 1),                                        // rla
         0x18 => jr!(),                                              // jr_n
         0x19 => add_hl!(r.de()),                                    // add_hlde
-        0x1a => { r.a = m.rb(r.de()); 2 }                           // ld_ade
+        0x1a => { r.a = m.rb_cycle(r.de()); 2 }                     // ld_ade
         0x1b => dec_16!(d, e),                                      // dec_de
         0x1c => inc!(e),                                            // inc_e
         0x1d => dec!(e),                                            // dec_e
@@ -338,7 +338,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
         0x20 => jr_n!(!r.f.z.get()),                                // jr_nz_n
         0x21 => ld_nn!(h, l),                                       // ld_hlnn
-        0x22 => { m.wb(r.hl(), r.a); r.inc_hl(); 2 },               // ld_hlma
+        0x22 => { m.wb_cycle(r.hl(), r.a); r.inc_hl(); 2 },         // ld_hlma
         0x23 => inc_16!(h, l),                                      // inc_hl
         0x24 => inc!(h),                                            // inc_h
         0x25 => dec!(h),                                            // dec_h
@@ -346,7 +346,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x27 => { daa!(r); 1 },                                     // daa
         0x28 => jr_n!(r.f.z.get()),                                 // jr_z_n
         0x29 => add_hl!(r.hl()),                                    // add_hlhl
-        0x2a => { r.a = m.rb(r.hl()); r.inc_hl(); 2 },              // ldi_ahlm
+        0x2a => { r.a = m.rb_cycle(r.hl()); r.inc_hl(); 2 },        // ldi_ahlm
         0x2b => dec_16!(h, l),                                      // dec_hl
         0x2c => inc!(l),                                            // inc_l
         0x2d => dec!(l),                                            // dec_l
@@ -354,16 +354,16 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x2f => { r.a ^= 0xff; r.f.n.set(); r.f.h.set(); 1 }        // cpl
 
         0x30 => jr_n!(!r.f.c.get()),                                // jr_nc_n
-        0x31 => { r.sp = m.rw(r.pc); r.pc += 2; 3 }                 // ld_spnn
-        0x32 => { m.wb(r.hl(), r.a); r.dec_hl(); 2 }                // ldd_hlma
+        0x31 => { r.sp = m.rw_cycles(r.pc); r.pc += 2; 3 }          // ld_spnn
+        0x32 => { m.wb_cycle(r.hl(), r.a); r.dec_hl(); 2 }          // ldd_hlma
         0x33 => { r.sp = r.sp.wrapping_add(1); 2 }                  // inc_sp
         0x34 => { r.inc_hlm(m); 3 }                                 // inc_hlm
         0x35 => { r.dec_hlm(m); 3 }                                 // dec_hlm
-        0x36 => { let v = m.rb(r.bump()); m.wb(r.hl(), v); 3 }      // ld_hlmn
+        0x36 => { let v = m.rb_cycle(r.bump()); m.wb_cycle(r.hl(), v); 3 } // ld_hlmn
         0x37 => { r.f.n.unset(); r.f.h.unset(); r.f.c.set(); 1 }    // scf
         0x38 => jr_n!(r.f.c.get()),                                 // jr_c_n
         0x39 => { r.add_hlsp(); 2 }                                 // add_hlsp
-        0x3a => { r.a = m.rb(r.hl()); r.dec_hl(); 2 }               // ldd_ahlm
+        0x3a => { r.a = m.rb_cycle(r.hl()); r.dec_hl(); 2 }         // ldd_ahlm
         0x3b => { r.sp = r.sp.wrapping_sub(1); 2 }                  // dec_sp
         //0x3c => {inc!(a); info!("inc a: {}",r.a); 1 },                                            // inc_a
         0x3c => inc!(a),                                            // inc_a
@@ -377,7 +377,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x43 => ld!(b, e),                                          // ld_be
         0x44 => ld!(b, h),                                          // ld_bh
         0x45 => ld!(b, l),                                          // ld_bl
-        0x46 => { r.b = m.rb(r.hl()); 2 }                           // ld_bhlm
+        0x46 => { r.b = m.rb_cycle(r.hl()); 2 }                     // ld_bhlm
         0x47 => ld!(b, a),                                          // ld_ba
         0x48 => ld!(c, b),                                          // ld_cb
         0x49 => ld!(c, c),                                          // ld_cc
@@ -385,7 +385,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x4b => ld!(c, e),                                          // ld_ce
         0x4c => ld!(c, h),                                          // ld_ch
         0x4d => ld!(c, l),                                          // ld_cl
-        0x4e => { r.c = m.rb(r.hl()); 2 }                           // ld_chlm
+        0x4e => { r.c = m.rb_cycle(r.hl()); 2 }                     // ld_chlm
         0x4f => ld!(c, a),                                          // ld_ca
 
         0x50 => ld!(d, b),                                          // ld_db
@@ -394,7 +394,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x53 => ld!(d, e),                                          // ld_de
         0x54 => ld!(d, h),                                          // ld_dh
         0x55 => ld!(d, l),                                          // ld_dl
-        0x56 => { r.d = m.rb(r.hl()); 2 }                           // ld_dhlm
+        0x56 => { r.d = m.rb_cycle(r.hl()); 2 }                     // ld_dhlm
         0x57 => ld!(d, a),                                          // ld_da
         0x58 => ld!(e, b),                                          // ld_eb
         0x59 => ld!(e, c),                                          // ld_ec
@@ -402,7 +402,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x5b => ld!(e, e),                                          // ld_ee
         0x5c => ld!(e, h),                                          // ld_eh
         0x5d => ld!(e, l),                                          // ld_el
-        0x5e => { r.e = m.rb(r.hl()); 2 }                           // ld_ehlm
+        0x5e => { r.e = m.rb_cycle(r.hl()); 2 }                     // ld_ehlm
         0x5f => ld!(e, a),                                          // ld_ea
 
         0x60 => ld!(h, b),                                          // ld_hb
@@ -411,7 +411,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x63 => ld!(h, e),                                          // ld_he
         0x64 => ld!(h, h),                                          // ld_hh
         0x65 => ld!(h, l),                                          // ld_hl
-        0x66 => { r.h = m.rb(r.hl()); 2 }                           // ld_hhlm
+        0x66 => { r.h = m.rb_cycle(r.hl()); 2 }                     // ld_hhlm
         0x67 => ld!(h, a),                                          // ld_ha
         0x68 => ld!(l, b),                                          // ld_lb
         0x69 => ld!(l, c),                                          // ld_lc
@@ -419,24 +419,24 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x6b => ld!(l, e),                                          // ld_le
         0x6c => ld!(l, h),                                          // ld_lh
         0x6d => ld!(l, l),                                          // ld_ll
-        0x6e => { r.l = m.rb(r.hl()); 2 }                           // ld_lhlm
+        0x6e => { r.l = m.rb_cycle(r.hl()); 2 }                     // ld_lhlm
         0x6f => ld!(l, a),                                          // ld_la
 
-        0x70 => { m.wb(r.hl(), r.b); 2 }                            // ld_hlmb
-        0x71 => { m.wb(r.hl(), r.c); 2 }                            // ld_hlmc
-        0x72 => { m.wb(r.hl(), r.d); 2 }                            // ld_hlmd
-        0x73 => { m.wb(r.hl(), r.e); 2 }                            // ld_hlme
-        0x74 => { m.wb(r.hl(), r.h); 2 }                            // ld_hlmh
-        0x75 => { m.wb(r.hl(), r.l); 2 }                            // ld_hlml
-        0x76 => { r.halt = true; 1 }                                // halt
-        0x77 => { m.wb(r.hl(), r.a); 2 }                            // ld_hlma
+        0x70 => { m.wb_cycle(r.hl(), r.b); 2 }                      // ld_hlmb
+        0x71 => { m.wb_cycle(r.hl(), r.c); 2 }                      // ld_hlmc
+        0x72 => { m.wb_cycle(r.hl(), r.d); 2 }                      // ld_hlmd
+        0x73 => { m.wb_cycle(r.hl(), r.e); 2 }                      // ld_hlme
+        0x74 => { m.wb_cycle(r.hl(), r.h); 2 }                      // ld_hlmh
+        0x75 => { m.wb_cycle(r.hl(), r.l); 2 }                      // ld_hlml
+        0x76 => { r.halt(m); 1 }                                    // halt
+        0x77 => { m.wb_cycle(r.hl(), r.a); 2 }                      // ld_hlma
         0x78 => ld!(a, b),                                          // ld_ab
         0x79 => ld!(a, c),                                          // ld_ac
         0x7a => ld!(a, d),                                          // ld_ad
         0x7b => ld!(a, e),                                          // ld_ae
         0x7c => ld!(a, h),                                          // ld_ah
         0x7d => ld!(a, l),                                          // ld_al
-        0x7e => { r.a = m.rb(r.hl()); 2 }                           // ld_ahlm
+        0x7e => { r.a = m.rb_cycle(r.hl()); 2 }                     // ld_ahlm
         0x7f => ld!(a, a),                                          // ld_aa
 
         0x80 => add_a!(r.b),                                        // add_ab
@@ -445,7 +445,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x83 => add_a!(r.e),                                        // add_ae
         0x84 => add_a!(r.h),                                        // add_ah
         0x85 => add_a!(r.l),                                        // add_al
-        0x86 => { add_a!(m.rb(r.hl())); 2 }                         // add_ahlm
+        0x86 => { add_a!(m.rb_cycle(r.hl())); 2 }                   // add_ahlm
         0x87 => add_a!(r.a),                                        // add_aa
         0x88 => adc_a!(r.b),                                        // adc_ab
         0x89 => adc_a!(r.c),                                        // adc_ac
@@ -453,7 +453,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x8b => adc_a!(r.e),                                        // adc_ae
         0x8c => adc_a!(r.h),                                        // adc_ah
         0x8d => adc_a!(r.l),                                        // adc_al
-        0x8e => { adc_a!(m.rb(r.hl())); 2 }                         // adc_ahlm
+        0x8e => { adc_a!(m.rb_cycle(r.hl())); 2 }                   // adc_ahlm
         0x8f => adc_a!(r.a),                                        // adc_aa
 
         0x90 => sub_a!(r.b),                                        // sub_ab
@@ -462,7 +462,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x93 => sub_a!(r.e),                                        // sub_ae
         0x94 => sub_a!(r.h),                                        // sub_ah
         0x95 => sub_a!(r.l),                                        // sub_al
-        0x96 => { sub_a!(m.rb(r.hl())); 2 }                         // sub_ahlm
+        0x96 => { sub_a!(m.rb_cycle(r.hl())); 2 }                   // sub_ahlm
         0x97 => sub_a!(r.a),                                        // sub_aa
         0x98 => sbc_a!(r.b),                                        // sbc_ab
         0x99 => sbc_a!(r.c),                                        // sbc_ac
@@ -470,7 +470,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x9b => sbc_a!(r.e),                                        // sbc_ae
         0x9c => sbc_a!(r.h),                                        // sbc_ah
         0x9d => sbc_a!(r.l),                                        // sbc_al
-        0x9e => { sbc_a!(m.rb(r.hl())); 2 }                         // sbc_ahlm
+        0x9e => { sbc_a!(m.rb_cycle(r.hl())); 2 }                   // sbc_ahlm
         0x9f => sbc_a!(r.a),                                        // sbc_aa
 
         0xa0 => and_a!(r.b),                                        // and_ab
@@ -479,7 +479,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xa3 => and_a!(r.e),                                        // and_ae
         0xa4 => and_a!(r.h),                                        // and_ah
         0xa5 => and_a!(r.l),                                        // and_al
-        0xa6 => { and_a!(m.rb(r.hl())); 2 }                         // and_ahlm
+        0xa6 => { and_a!(m.rb_cycle(r.hl())); 2 }                   // and_ahlm
         0xa7 => and_a!(r.a),                                        // and_aa
         0xa8 => xor_a!(r.b),                                        // xor_ab
         0xa9 => xor_a!(r.c),                                        // xor_ac
@@ -487,7 +487,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xab => xor_a!(r.e),                                        // xor_ae
         0xac => xor_a!(r.h),                                        // xor_ah
         0xad => xor_a!(r.l),                                        // xor_al
-        0xae => { xor_a!(m.rb(r.hl())); 2 }                         // xor_ahlm
+        0xae => { xor_a!(m.rb_cycle(r.hl())); 2 }                   // xor_ahlm
         0xaf => xor_a!(r.a),                                        // xor_aa
 
         0xb0 => or_a!(r.b),                                         // or_ab
@@ -496,7 +496,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xb3 => or_a!(r.e),                                         // or_ae
         0xb4 => or_a!(r.h),                                         // or_ah
         0xb5 => or_a!(r.l),                                         // or_al
-        0xb6 => { or_a!(m.rb(r.hl())); 2 }                          // or_ahlm
+        0xb6 => { or_a!(m.rb_cycle(r.hl())); 2 }                    // or_ahlm
         0xb7 => or_a!(r.a),                                         // or_aa
         0xb8 => cp_a!(r.b),                                         // cp_ab
         0xb9 => cp_a!(r.c),                                         // cp_ac
@@ -504,34 +504,34 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xbb => cp_a!(r.e),                                         // cp_ae
         0xbc => cp_a!(r.h),                                         // cp_ah
         0xbd => cp_a!(r.l),                                         // cp_al
-        0xbe => { cp_a!(m.rb(r.hl())); 2 }                          // cp_ahlm
+        0xbe => { cp_a!(m.rb_cycle(r.hl())); 2 }                    // cp_ahlm
         0xbf => cp_a!(r.a),                                         // cp_aa
 
         0xc0 => ret_if!(!r.f.z.get()),                              // ret_nz
-        0xc1 => {let sp=r.sp; r.bc_set(m.rw(sp)); r.sp += 2; 3},    // pop_bc
+        0xc1 => {let sp=r.sp; r.bc_set(m.rw_cycles(sp)); r.sp += 2; 3}, // pop_bc
         //0xc2 => { warn!("jp at {:04X}",r.pc);jp_n!(!r.f.z.get())},                                // jp_nz_nn
         0xc2 => jp_n!(!r.f.z.get()),                                // jp_nz_nn
         0xc3 => jp!(),                                              // jp_nn
         0xc4 => call_if!(!r.f.z.get()),                             // call_nz_n
         0xc5 => push!(bc),                                          // push_bc
-        0xc6 => { add_a!(m.rb(r.bump())); 2 }                       // add_an
+        0xc6 => { add_a!(m.rb_cycle(r.bump())); 2 }                 // add_an
         0xc7 => rst!(0x00),                                         // rst_00
         0xc8 => ret_if!(r.f.z.get()),                               // ret_z
         0xc9 => { r.ret(m); 4 }                                     // ret
         0xca => jp_n!(r.f.z.get()),                                 // jp_z_nn
-        0xcb => { exec_cb(m.rb(r.bump()), r, m) }                   // map_cb
+        0xcb => { exec_cb(m.rb_cycle(r.bump()), r, m) }             // map_cb
         0xcc => call_if!(r.f.z.get()),                              // call_z_n
         0xcd => call!(),                                            // call
-        0xce => { adc_a!(m.rb(r.bump())); 2 }                       // adc_an
+        0xce => { adc_a!(m.rb_cycle(r.bump())); 2 }                 // adc_an
         0xcf => rst!(0x08),                                         // rst_08
 
         0xd0 => ret_if!(!r.f.c.get()),                              // ret_nc
-        0xd1 => {let sp=r.sp; r.de_set(m.rw(sp)); r.sp += 2; 3},    // pop_de
+        0xd1 => {let sp=r.sp; r.de_set(m.rw_cycles(sp)); r.sp += 2; 3}, // pop_de
         0xd2 => jp_n!(!r.f.c.get()),                                // jp_nc_nn
         0xd3 => xx(),                                               // xx
         0xd4 => call_if!(!r.f.c.get()),                             // call_nc_n
         0xd5 => push!(de),                                          // push_de
-        0xd6 => { sub_a!(m.rb(r.bump())); 2 }                       // sub_an
+        0xd6 => { sub_a!(m.rb_cycle(r.bump())); 2 }                 // sub_an
         0xd7 => rst!(0x10),                                         // rst_10
         0xd8 => ret_if!(r.f.c.get()),                               // ret_c
         0xd9 => { r.ei(m); r.ret(m); 4 }                            // reti
@@ -539,43 +539,43 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xdb => xx(),                                               // xx
         0xdc => call_if!(r.f.c.get()),                              // call_c_n
         0xdd => xx(),                                               // xx
-        0xde => { sbc_a!(m.rb(r.bump())); 2 }                       // sbc_an
+        0xde => { sbc_a!(m.rb_cycle(r.bump())); 2 }                 // sbc_an
         0xdf => rst!(0x18),                                         // rst_18
 
-        0xe0 => {let n=m.rb(r.bump());
-            m.wb(0xFF00 | n as u16, r.a); 3 }                       // ld_IOan
-        0xe1 => {let sp=r.sp; r.hl_set(m.rw(sp)); r.sp += 2; 3},    // pop_hl
-        0xe2 => { m.wb(0xFF00 | (r.c as u16), r.a); 2 }             // ld_IOca
+        0xe0 => {let n=m.rb_cycle(r.bump());
+            m.wb_cycle(0xFF00 | n as u16, r.a); 3 }                       // ld_IOan
+        0xe1 => {let sp=r.sp; r.hl_set(m.rw_cycles(sp)); r.sp += 2; 3}, // pop_hl
+        0xe2 => { m.wb_cycle(0xFF00 | (r.c as u16), r.a); 2 }       // ld_IOca
         0xe3 => xx(),                                               // xx
         0xe4 => xx(),                                               // xx
         0xe5 => push!(hl),                                          // push_hl
-        0xe6 => and_a!(m.rb(r.bump())),                             // and_an
+        0xe6 => { and_a!(m.rb_cycle(r.bump())); 2 }                 // and_an
         //0xe6 => {and_a!(m.rb(r.bump())); warn!("and a:{:02X}",r.a); 2 }                       // and_an
         0xe7 => rst!(0x20),                                         // rst_20
         0xe8 => { add_spn(r, m); 4 }                                // add_spn
         0xe9 => { r.pc = r.hl(); 1 }                                // jp_hl
-        0xea => { let n = m.rw(r.pc); m.wb(n, r.a); r.pc += 2; 4 }  // ld_nna
+        0xea => { let n = m.rw_cycles(r.pc); m.wb_cycle(n, r.a); r.pc += 2; 4 } // ld_nna
         0xeb => xx(),                                               // xx
         0xec => xx(),                                               // xx
         0xed => xx(),                                               // xx
-        0xee => { xor_a!(m.rb(r.bump())); 2 }                       // xor_an
+        0xee => { xor_a!(m.rb_cycle(r.bump())); 2 }                 // xor_an
         0xef => rst!(0x28),                                         // rst_28
 
         0xf0 => ld_aIOn!(),                                         // ld_aIOn
-        0xf1 => { let sp=r.sp; r.af_set(m.rw(sp)); r.sp += 2; 3 },  // pop_af
-        0xf2 => { r.a = m.rb(0xff00 | (r.c as u16)); 2 }            // ld_aIOc
+        0xf1 => { let sp=r.sp; r.af_set(m.rw_cycles(sp)); r.sp += 2; 3 }, // pop_af
+        0xf2 => { r.a = m.rb_cycle(0xff00 | (r.c as u16)); 2 }      // ld_aIOc
         0xf3 => { r.di(); 1 }                                       // di
         0xf4 => xx(),                                               // xx
         0xf5 => push!(af),                                          // push_af
-        0xf6 => { or_a!(m.rb(r.bump())); 2 }                        // or_an
+        0xf6 => { or_a!(m.rb_cycle(r.bump())); 2 }                  // or_an
         0xf7 => rst!(0x30),                                         // rst_30
         0xf8 => { ld_hlspn!() }                                     // ld_hlspn
         0xf9 => { r.sp = r.hl(); 2 }                                // ld_sphl
-        0xfa => { let b = m.rw(r.pc); r.a = m.rb(b); r.pc += 2; 4 } // ld_ann
+        0xfa => { let b = m.rw_cycles(r.pc); r.a = m.rb_cycle(b); r.pc += 2; 4 } // ld_ann
         0xfb => { r.ei(m); 1 }                                      // ei
         0xfc => xx(),                                               // xx
         0xfd => xx(),                                               // xx
-        0xfe => { cp_a!(m.rb(r.bump())); 2 }                        // cp_an
+        0xfe => { cp_a!(m.rb_cycle(r.bump())); 2 }                  // cp_an
         0xff => rst!(0x38),                                         // rst_38
 
         _ => {
@@ -591,7 +591,7 @@ fn add_signed(a: u16, b: u8) -> u16 {
 }
 
 fn add_spn(r: &mut Registers, m: &mut mmu::Memory) {
-    let b = m.rb(r.bump()) as i8 as i16 as u16;
+    let b = m.rb_cycle(r.bump()) as i8 as i16 as u16;
     let res = r.sp.wrapping_add(b);
     let tmp = b ^ res ^ r.sp;
     r.f.c.set_if(tmp & 0x100 != 0);
@@ -647,20 +647,20 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         $cy as u32
     }) );
     macro_rules! hlm( ($i:ident, $s:stmt) => ({
-        let mut $i = m.rb(r.hl());
+        let mut $i = m.rb_cycle(r.hl());
         r.f.h.unset(); r.f.n.unset();
         $s;
-        m.wb(r.hl(), $i);
+        m.wb_cycle(r.hl(), $i);
     }) );
     macro_rules! hlfrob( ($e:expr) => ({
-        let hl = m.rb(r.hl());
+        let hl = m.rb_cycle(r.hl());
         //r.f.h.unset(); r.f.n.unset();
-        m.wb(r.hl(), hl & $e);
+        m.wb_cycle(r.hl(), hl & $e);
     }) );
     macro_rules! hlfrob_or( ($e:expr) => ({
-        let hl = m.rb(r.hl());
+        let hl = m.rb_cycle(r.hl());
         //r.f.h.unset(); r.f.n.unset();
-        m.wb(r.hl(), hl | $e);
+        m.wb_cycle(r.hl(), hl | $e);
     }) );
     macro_rules! sra( ($e:expr, $cy:expr) => ({
         let co = $e & 1;
@@ -776,7 +776,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x43 => bit!(r.e, 0),                                       // bit_0e
         0x44 => bit!(r.h, 0),                                       // bit_0h
         0x45 => bit!(r.l, 0),                                       // bit_0l
-        0x46 => { bit!(m.rb(r.hl()), 0); 3 }                        // bit_0hlm
+        0x46 => { bit!(m.rb_cycle(r.hl()), 0); 3 }                  // bit_0hlm
         0x47 => bit!(r.a, 0),                                       // bit_0a
         0x48 => bit!(r.b, 1),                                       // bit_1b
         0x49 => bit!(r.c, 1),                                       // bit_1c
@@ -784,7 +784,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x4b => bit!(r.e, 1),                                       // bit_1e
         0x4c => bit!(r.h, 1),                                       // bit_1h
         0x4d => bit!(r.l, 1),                                       // bit_1l
-        0x4e => { bit!(m.rb(r.hl()), 1); 3 }                        // bit_1hlm
+        0x4e => { bit!(m.rb_cycle(r.hl()), 1); 3 }                  // bit_1hlm
         0x4f => bit!(r.a, 1),                                       // bit_1a
 
         0x50 => bit!(r.b, 2),                                       // bit_2b
@@ -793,7 +793,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x53 => bit!(r.e, 2),                                       // bit_2e
         0x54 => bit!(r.h, 2),                                       // bit_2h
         0x55 => bit!(r.l, 2),                                       // bit_2l
-        0x56 => { bit!(m.rb(r.hl()), 2); 3 }                        // bit_2hlm
+        0x56 => { bit!(m.rb_cycle(r.hl()), 2); 3 }                  // bit_2hlm
         0x57 => bit!(r.a, 2),                                       // bit_2a
         0x58 => bit!(r.b, 3),                                       // bit_3b
         0x59 => bit!(r.c, 3),                                       // bit_3c
@@ -801,7 +801,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x5b => bit!(r.e, 3),                                       // bit_3e
         0x5c => bit!(r.h, 3),                                       // bit_3h
         0x5d => bit!(r.l, 3),                                       // bit_3l
-        0x5e => { bit!(m.rb(r.hl()), 3); 3 }                        // bit_3hlm
+        0x5e => { bit!(m.rb_cycle(r.hl()), 3); 3 }                  // bit_3hlm
         0x5f => bit!(r.a, 3),                                       // bit_3a
 
         0x60 => bit!(r.b, 4),                                       // bit_4b
@@ -810,7 +810,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x63 => bit!(r.e, 4),                                       // bit_4e
         0x64 => bit!(r.h, 4),                                       // bit_4h
         0x65 => bit!(r.l, 4),                                       // bit_4l
-        0x66 => { bit!(m.rb(r.hl()), 4); 3 }                        // bit_4hlm
+        0x66 => { bit!(m.rb_cycle(r.hl()), 4); 3 }                  // bit_4hlm
         0x67 => bit!(r.a, 4),                                       // bit_4a
         0x68 => bit!(r.b, 5),                                       // bit_5b
         0x69 => bit!(r.c, 5),                                       // bit_5c
@@ -818,7 +818,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x6b => bit!(r.e, 5),                                       // bit_5e
         0x6c => bit!(r.h, 5),                                       // bit_5h
         0x6d => bit!(r.l, 5),                                       // bit_5l
-        0x6e => { bit!(m.rb(r.hl()), 5); 3 }                        // bit_5hlm
+        0x6e => { bit!(m.rb_cycle(r.hl()), 5); 3 }                  // bit_5hlm
         0x6f => bit!(r.a, 5),                                       // bit_5a
 
         0x70 => bit!(r.b, 6),                                       // bit_6b
@@ -827,7 +827,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x73 => bit!(r.e, 6),                                       // bit_6e
         0x74 => bit!(r.h, 6),                                       // bit_6h
         0x75 => bit!(r.l, 6),                                       // bit_6l
-        0x76 => { bit!(m.rb(r.hl()), 6); 3 }                        // bit_6hlm
+        0x76 => { bit!(m.rb_cycle(r.hl()), 6); 3 }                  // bit_6hlm
         0x77 => bit!(r.a, 6),                                       // bit_6a
         0x78 => bit!(r.b, 7),                                       // bit_7b
         0x79 => bit!(r.c, 7),                                       // bit_7c
@@ -835,7 +835,7 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x7b => bit!(r.e, 7),                                       // bit_7e
         0x7c => bit!(r.h, 7),                                       // bit_7h
         0x7d => bit!(r.l, 7),                                       // bit_7l
-        0x7e => { bit!(m.rb(r.hl()), 7); 3 }                        // bit_7hlm
+        0x7e => { bit!(m.rb_cycle(r.hl()), 7); 3 }                  // bit_7hlm
         0x7f => bit!(r.a, 7),                                       // bit_7a
 
         0x80 => { r.b &= !(1 << 0); 2 }                             // res_0b
@@ -7,12 +7,14 @@
 pub mod instructions;
 
 use std::str;
-use std::fmt;
+use std::{fmt, io};
 use std::io::prelude::*;
 use std::fs::{OpenOptions, File};
+use std::path::Path;
 
 use colored::*;
 use mmu::Memory;
+use state::{StateWriter, StateReader};
 
 
 // CPU Clock speed
@@ -29,6 +31,15 @@ pub enum Interrupt {
     Joypad  = 0x10,
 }
 
+// Indexed by the interrupt's bit in IE/IF, ISRs are at 0x40 + 8 * bit
+const INTERRUPT_NAMES: [&'static str; 5] = [
+    "VBLANK",
+    "LCD status triggers",
+    "Timer overflow",
+    "Serial link",
+    "Joypad press",
+];
+
 // Tell the compiler to generate a default() function
 // Which zero initializes everything
 
@@ -37,6 +48,7 @@ pub enum Interrupt {
 pub struct Registers  {
     pub ime: bool,
     halt: bool,
+    halt_bug: bool,     // the next opcode fetch doesn't move PC
     pub stop: bool,
 
     a: u8,      // A: Accumulator
@@ -103,6 +115,17 @@ impl Registers {
         info!("Enable interrupts, delay: {}", self.delay);
     }
 
+    // With IME clear and an interrupt already pending, HALT doesn't halt.
+    // Instead the next opcode is read twice, PC fails to move past it.
+    fn halt(&mut self, m: &Memory) {
+        if !self.ime && m.ie_ & m.if_ & 0x1F != 0 {
+            debug!("HALT bug at {:04X}", self.pc);
+            self.halt_bug = true;
+        } else {
+            self.halt = true;
+        }
+    }
+
     pub fn di(&mut self) {
         info!("Disable interrupts");
         self.ime = false;
@@ -125,8 +148,17 @@ impl Registers {
         }
     }
 
+    // Takes an internal cycle, then writes the high byte first
+    fn push(&mut self, m: &mut Memory, val: u16) {
+        m.tick();
+        self.sp = self.sp.wrapping_sub(1);
+        m.wb_cycle(self.sp, (val >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        m.wb_cycle(self.sp, val as u8);
+    }
+
     fn ret(&mut self, m: &mut Memory) {
-        self.pc = m.rw(self.sp);
+        self.pc = m.rw_cycles(self.sp);
         debug!("RET to {:04X}", self.pc);
         self.sp += 2;
     }
@@ -134,8 +166,8 @@ impl Registers {
     fn inc_hlm(&mut self, m: &mut Memory) {
         self.f.n.unset();
         let hl = self.hl();
-        let v = m.rb(hl).wrapping_add(1);
-        m.wb(hl, v);
+        let v = m.rb_cycle(hl).wrapping_add(1);
+        m.wb_cycle(hl, v);
         if v == 0 {self.f.z.set()} else {self.f.z.unset()};
         if v & 0xF == 0 {self.f.h.set()} else {self.f.h.unset()};
     }
@@ -143,8 +175,8 @@ impl Registers {
     fn dec_hlm(&mut self, m: &mut Memory) {
         self.f.n.set();
         let hl = self.hl();
-        let v = m.rb(hl).wrapping_sub(1);
-        m.wb(hl, v);
+        let v = m.rb_cycle(hl).wrapping_sub(1);
+        m.wb_cycle(hl, v);
         if v == 0 {self.f.z.set()} else {self.f.z.unset()};
         if v & 0xF == 0xF {self.f.h.set()} else {self.f.h.unset()};
     }
@@ -214,16 +246,6 @@ macro_rules! flag_to_ch (
     })
 );
 
-// Interrupt handlers
-macro_rules! rst (
-($sel:ident, $mem:ident, $isr:expr) => ({
-    $sel.regs.ime = false;
-    $sel.regs.sp -= 2;
-    $mem.ww($sel.regs.sp, $sel.regs.pc);
-    $sel.regs.pc = $isr;
-    $sel.total_cycles += 12;
-}) );
-
 pub struct Cpu {
     regs: Registers,
 
@@ -240,13 +262,6 @@ impl Cpu {
             is_running: true,
             trace_file: None,
         };
-        cpu.trace_file = Some(OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .truncate(true)
-                            .open("trace_log.txt")
-                            .unwrap());
-
         cpu.reset_state();
         cpu
     }
@@ -264,6 +279,28 @@ impl Cpu {
         self.regs.pc = 0x0100;
     }
 
+    // Power Up Sequence of the CGB. Games check for A = 0x11 to tell they're
+    // running on one.
+    pub fn reset_state_cgb(&mut self) {
+        self.reset_state();
+        self.regs.a = 0x11;
+        self.regs.f.reset();
+        self.regs.f.z.set();
+        self.regs.bc_set(0x0000);
+        self.regs.de_set(0xFF56);
+        self.regs.hl_set(0x000D);
+    }
+
+    // Starts writing a WADATSUMI_DEBUG trace line per instruction to the file
+    pub fn enable_trace<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.trace_file = Some(try!(OpenOptions::new()
+                            .create(true)
+                            .write(true)
+                            .truncate(true)
+                            .open(path)));
+        Ok(())
+    }
+
     pub fn get_regs(&self) -> &Registers {
         &self.regs
     }
@@ -277,12 +314,49 @@ impl Cpu {
         &mut self.regs.f
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.regs;
+        w.u16(r.af());
+        w.u16(r.bc());
+        w.u16(r.de());
+        w.u16(r.hl());
+        w.u16(r.sp);
+        w.u16(r.pc);
+        w.bool(r.ime);
+        w.bool(r.halt);
+        w.bool(r.halt_bug);
+        w.bool(r.stop);
+        w.u32(r.delay);
+        w.u32(self.total_cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let regs = &mut self.regs;
+        regs.af_set(try!(r.u16()));
+        regs.bc_set(try!(r.u16()));
+        regs.de_set(try!(r.u16()));
+        regs.hl_set(try!(r.u16()));
+        regs.sp = try!(r.u16());
+        regs.pc = try!(r.u16());
+        regs.ime = try!(r.bool());
+        regs.halt = try!(r.bool());
+        regs.halt_bug = try!(r.bool());
+        regs.stop = try!(r.bool());
+        regs.delay = try!(r.u32());
+        self.total_cycles = try!(r.u32());
+        Ok(())
+    }
+
     // Dispatcher
     // Executes 1 instruction
     pub fn exec(&mut self, mem: &mut Memory) -> u32 {
 
         // Interrupts
-        if self.handle_interrupts(mem) {return 0};
+        if self.handle_interrupts(mem) {
+            let cycles = mem.finish_cycles(20);
+            self.total_cycles += cycles;
+            return cycles;
+        }
 
         // Fetch opcode
         let op: u8 = mem.rb(self.regs.pc);
@@ -290,7 +364,7 @@ impl Cpu {
         // Save previous pc
         let pc_before = self.regs.pc;
 
-        if WADATSUMI_DEBUG {
+        if WADATSUMI_DEBUG && self.trace_file.is_some() {
             let ff44 = mem.rb(0xff44);
             // let line = format!("PC[0x{:02X}]: 0x{:04X} AF: 0x{:04X} BC: 0x{:04X} DE: 0x{:04X} HL: 0x{:04X} SP: 0x{:04X} IE: {:08b} IF: {:08b} DIV: {} LY: {:02X}\n",
             let line = format!("PC[0x{:02X}]: 0x{:04X} AF: 0x{:04X} BC: 0x{:04X} DE: 0x{:04X} HL: 0x{:04X} SP: 0x{:04X} IE: {:08b} IF: {:08b}\n",
@@ -313,26 +387,37 @@ impl Cpu {
             trace_file.write_all(line.as_bytes()).unwrap();
         }
 
-        // OAM DMA
-        if mem.is_dma {
-            mem.handle_dma_transfer();
+        // CGB VRAM DMA, the CPU waits for the copy
+        let hdma_cycles = mem.handle_hdma();
+        if hdma_cycles > 0 {
+            return mem.finish_cycles(hdma_cycles);
         }
 
-        // HALT
+        // HALT. A pending interrupt wakes the CPU up even with IME clear, it
+        // just isn't serviced then.
         if self.regs.halt {
-            if mem.ie_ & mem.if_ != 0 {
+            if mem.ie_ & mem.if_ & 0x1F != 0 {
                 self.regs.halt = false;
             }
         }
         if self.regs.halt {
-            return 4;
+            return mem.finish_cycles(4);
         }
 
+        // The opcode was read above, this is the M-cycle fetching it
+        mem.tick();
+
         // Increment PC
-        self.regs.pc += 1;
+        if self.regs.halt_bug {
+            self.regs.halt_bug = false;
+        } else {
+            self.regs.pc += 1;
+        }
 
-        // Execute instruction
+        // Execute instruction. Memory accesses run the other components as
+        // they happen, the cycles left are run after.
         let cycles = instructions::exec(op, &mut self.regs, mem) * 4;
+        let cycles = mem.finish_cycles(cycles);
 
         if INSTR_DEBUG {
             let pc_diff = self.regs.pc as i32 - pc_before as i32;
@@ -377,48 +462,47 @@ impl Cpu {
         return cycles;
     }
 
+    // Services the highest priority pending interrupt, if any and if IME
+    // allows it. Takes 5 M-cycles: 2 waiting, 2 pushing PC and 1 jumping,
+    // plus 1 to wake up when halted.
+    //
+    // Which interrupt gets serviced is only decided after the high byte of PC
+    // was pushed. When that push lands on IE (SP at 0x0000) and disables the
+    // interrupt, nothing is serviced and PC ends up at 0x0000.
     fn handle_interrupts(&mut self, mem: &mut Memory) -> bool {
         self.regs.int_step();
-        let interrupts = mem.ie_ & mem.if_;
-            macro_rules! print_interrupt (
-            ($s:expr) => {
-                warn!("{} IF: {:#08b}", $s.magenta(), mem.if_);
-            } );
-
-        if self.regs.ime && (interrupts != 0) {
-            // Vertical blank (ISR: 40 )
-            if interrupts & Interrupt::Vblank as u8 != 0 {
-                mem.if_ &= !(Interrupt::Vblank as u8);
-                rst!(self, mem, 0x40);
-                print_interrupt!("VBLANK");
-            }
-            // LCD status triggers (ISR: 48 )
-            if interrupts & Interrupt::LCDStat as u8 != 0 {
-                mem.if_ &= !(Interrupt::LCDStat as u8);
-                rst!(self, mem, 0x48);
-                print_interrupt!("LCD status triggers");
-            }
-            // Timer overflow (ISR: 50 )
-            if interrupts & Interrupt::Timer as u8 != 0 {
-                mem.if_ &= !(Interrupt::Timer as u8);
-                rst!(self, mem, 0x50);
-                print_interrupt!("Timer overflow");
-            }
-            // Serial link (ISR: 58 )
-            if interrupts & Interrupt::Serial as u8 != 0 {
-                mem.if_ &= !(Interrupt::Serial as u8);
-                rst!(self, mem, 0x58);
-                print_interrupt!("Serial link");
-            }
-            // LCD status triggers (ISR: 60 )
-            if interrupts & Interrupt::Joypad as u8 != 0 {
-                mem.if_ &= !(Interrupt::Joypad as u8);
-                rst!(self, mem, 0x60);
-                print_interrupt!("Joypad press");
-            }
-            return true;
+        if !self.regs.ime || mem.ie_ & mem.if_ & 0x1F == 0 {
+            return false;
+        }
+
+        self.regs.ime = false;
+        if self.regs.halt {
+            self.regs.halt = false;
+            mem.tick();
         }
-        return false;
+        mem.tick();
+        mem.tick();
+
+        let pc = self.regs.pc;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        mem.wb_cycle(self.regs.sp, (pc >> 8) as u8);
+
+        // The lowest bit has the highest priority
+        let interrupts = mem.ie_ & mem.if_ & 0x1F;
+        self.regs.pc = if interrupts != 0 {
+            let bit = interrupts.trailing_zeros();
+            mem.if_ &= !(1 << bit);
+            warn!("{} IF: {:#08b}", INTERRUPT_NAMES[bit as usize].magenta(), mem.if_);
+            0x40 + 8 * bit as u16
+        } else {
+            warn!("Interrupt cancelled by the push writing IE");
+            0x0000
+        };
+
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        mem.wb_cycle(self.regs.sp, pc as u8);
+        mem.tick();
+        true
     }
 
 }
@@ -505,4 +589,126 @@ mod cpu_tests {
         assert_eq!(flags.h.get(),   true);
         assert_eq!(flags.c.get(),   false);
     }
+
+    #[test]
+    fn memory_access_timing() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+
+        // ld (0xFF04), sp
+        for (i, &byte) in [0x08, 0x04, 0xFF].iter().enumerate() {
+            mem.wb(0xC000 + i as u16, byte);
+        }
+        cpu.regs.pc = 0xC000;
+        cpu.regs.sp = 0x1234;
+
+        assert_eq!(cpu.exec(&mut mem), 20);
+        // The low byte resets DIV in the 4th M-cycle, the 5th writes TIMA
+        assert_eq!(mem.timer.div, 4);
+        assert_eq!(mem.timer.tima, 0x12);
+    }
+
+    #[test]
+    fn interrupt_dispatch() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        cpu.regs.pc = 0xC123;
+        cpu.regs.sp = 0xD000;
+        cpu.regs.ime = true;
+        mem.ie_ = 0x1F;
+        mem.if_ = (Interrupt::Timer as u8) | (Interrupt::LCDStat as u8);
+
+        // Only the one with the highest priority
+        assert_eq!(cpu.exec(&mut mem), 20);
+        assert_eq!(cpu.regs.pc, 0x48);
+        assert_eq!(mem.if_ & 0x1F, Interrupt::Timer as u8);
+        assert_eq!(cpu.regs.sp, 0xCFFE);
+        assert_eq!(mem.rw(0xCFFE), 0xC123);
+        assert!(!cpu.regs.ime);
+    }
+
+    #[test]
+    fn interrupt_cancelled_by_ie_push() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        cpu.regs.ime = true;
+        cpu.regs.sp = 0x0000;
+        mem.ie_ = Interrupt::Timer as u8;
+        mem.if_ = Interrupt::Timer as u8;
+
+        // The high byte of PC goes to IE and leaves the timer enabled
+        cpu.regs.pc = 0x0423;
+        cpu.exec(&mut mem);
+        assert_eq!(cpu.regs.pc, 0x50);
+        assert_eq!(mem.ie_, 0x04);
+
+        // Now it disables it
+        cpu.regs.ime = true;
+        cpu.regs.sp = 0x0000;
+        cpu.regs.pc = 0x0223;
+        mem.if_ = Interrupt::Timer as u8;
+        assert_eq!(cpu.exec(&mut mem), 20);
+        assert_eq!(cpu.regs.pc, 0x0000);
+        assert_eq!(mem.ie_, 0x02);
+        assert_eq!(mem.if_ & 0x1F, Interrupt::Timer as u8);
+        assert_eq!(mem.rb(0xFFFE), 0x23);
+    }
+
+    // Runs `code` from 0xC000 with IME clear and the timer interrupt enabled
+    fn halt_setup(code: &[u8]) -> (Cpu, Memory) {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        for (i, &byte) in code.iter().enumerate() {
+            mem.wb(0xC000 + i as u16, byte);
+        }
+        cpu.regs.pc = 0xC000;
+        cpu.regs.a = 0;
+        mem.ie_ = Interrupt::Timer as u8;
+        mem.if_ = 0;
+        (cpu, mem)
+    }
+
+    #[test]
+    fn halt_bug() {
+        // halt, inc a
+        let (mut cpu, mut mem) = halt_setup(&[0x76, 0x3C]);
+        mem.if_ = Interrupt::Timer as u8;
+
+        // HALT doesn't halt and INC A runs twice
+        cpu.exec(&mut mem);
+        assert!(!cpu.regs.halt);
+        cpu.exec(&mut mem);
+        assert_eq!(cpu.regs.pc, 0xC001);
+        cpu.exec(&mut mem);
+        assert_eq!(cpu.regs.pc, 0xC002);
+        assert_eq!(cpu.regs.a, 2);
+    }
+
+    #[test]
+    fn halt_wake_up() {
+        // halt, inc a
+        let (mut cpu, mut mem) = halt_setup(&[0x76, 0x3C]);
+        cpu.exec(&mut mem);
+        assert!(cpu.regs.halt);
+        assert_eq!(cpu.exec(&mut mem), 4);
+        assert!(cpu.regs.halt);
+
+        // Without IME the interrupt isn't serviced, the CPU carries on
+        mem.if_ = Interrupt::Timer as u8;
+        cpu.exec(&mut mem);
+        assert!(!cpu.regs.halt);
+        assert_eq!(cpu.regs.pc, 0xC002);
+        assert_eq!(cpu.regs.a, 1);
+        assert_eq!(mem.if_ & 0x1F, Interrupt::Timer as u8);
+
+        // With IME, waking up takes an extra M-cycle before the dispatch
+        let (mut cpu, mut mem) = halt_setup(&[0x76, 0x3C]);
+        cpu.regs.ime = true;
+        cpu.regs.sp = 0xD000;
+        cpu.exec(&mut mem);
+        mem.if_ = Interrupt::Timer as u8;
+        assert_eq!(cpu.exec(&mut mem), 24);
+        assert_eq!(cpu.regs.pc, 0x50);
+        assert_eq!(mem.rw(0xCFFE), 0xC001);
+    }
 }
@@ -1,19 +1,43 @@
-use piston::input::*;
-use piston_window::{PistonWindow, Texture};
-use gfx_device_gl::Resources as R;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufWriter;
 use std::{io, fmt};
-use std::path::Path;
-use piston::window::Window;
+use std::path::{Path, PathBuf};
 
 use cpu::Cpu;
 use mmu::Memory;
+use gpu::{ScreenData, GpuEvent};
 use cartridge::*;
+use state::{self, StateWriter, StateReader};
+use wav::WavRecorder;
+use palette::{self, ColorScheme};
 
 // Clock cycles between every screen refresh
 pub const SCREEN_REFRESH_INTERVAL: u32 = 70224; // clock cycles
+// Frames between writes of battery backed RAM to the save file (~10 seconds)
+pub const SAVE_INTERVAL: u32 = 600;
+
+// Receives every completed frame. Implemented by the frontend to get the
+// screen contents on display, the emulator core knows nothing about windows.
+pub trait FrameOutput {
+    fn frame(&mut self, data: &ScreenData);
+
+    // Audio produced during the frame, interleaved stereo at the APU's sample
+    // rate. Ignored by default.
+    fn audio(&mut self, samples: &[i16]) {}
+
+    // The LCD being turned off or on, before the frame it happened in.
+    // Ignored by default.
+    fn gpu_event(&mut self, event: GpuEvent) {}
+}
+
+// Frame output that throws frames away, for running headless
+pub struct NullOutput;
+
+impl FrameOutput for NullOutput {
+    fn frame(&mut self, data: &ScreenData) {}
+}
 
 pub struct Emulator {
     pub cpu: Cpu,
@@ -24,60 +48,89 @@ pub struct Emulator {
     is_instr_stepping: bool,
     is_debugging: bool,
     frame_cycles: u32, // cycles left until the frame ends
+    clock_cycles: u32, // at normal speed, the CPU's own count runs twice as fast in double speed mode
     pub frame_count: u32,
+
+    save_path: Option<PathBuf>, // .sav file for battery backed RAM
+    last_save: Vec<u8>,         // What's in the save file, to skip writes when nothing changed
+
+    wav: Option<WavRecorder<BufWriter<File>>>,  // Audio recording, if one is running
+
+    scheme_index: usize,    // Last built in color scheme picked by next_color_scheme()
 }
 
 impl Emulator {
-    pub fn new<W: Window>(window: &PistonWindow<W>, rom_path: &String) -> Emulator {
+    // Creates an emulator running the given ROM image. Doesn't need a window,
+    // frames are handed out through a FrameOutput in update(). Fails if the
+    // ROM is too small to have a header or the cartridge type isn't supported.
+    pub fn new(rom: Vec<u8>) -> io::Result<Emulator> {
+        if rom.len() < HEADER_END {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("ROM too small for a cartridge header: {} bytes", rom.len())));
+        }
+
         let mut emu = Emulator {
             cpu: Cpu::new(),
-            mem: Memory::new(window),
+            mem: Memory::new(),
             rom_header: Default::default(),
             is_frame_stepping: false,
             is_instr_stepping: false,
             is_debugging: true,
             frame_cycles: 0,
+            clock_cycles: 0,
             frame_count: 0,
+            save_path: None,
+            last_save: Vec::new(),
+            wav: None,
+            scheme_index: 0,
         };
 
-        // Read rom and move ownership to memory component
-        emu.mem.set_rom(try_open_rom(&rom_path));
-        emu.read_header();
+        emu.rom_header = read_header_impl(&rom);
+        if emu.rom_header.is_cgb() {
+            info!("Running in CGB mode");
+            emu.mem.set_cgb_mode();
+            emu.cpu.reset_state_cgb();
+        }
 
         // If the rom is more than 32KB, it has VRAM so we need to copy it
         if emu.rom_header.rom_size > 0 {
-            emu.mem.copy_vram();
+            emu.mem.copy_vram(&rom);
         }
-        emu.mem.copy_rom();
 
-        emu.mem.find_mbc(emu.rom_header.cartridge_type);
+        // Move ownership of the rom to memory component
+        try!(emu.mem.load_cartridge(rom, emu.rom_header.cartridge_type, emu.rom_header.ram_size));
 
         // Give immutable reference of rom header to memory component
         //emu.mem.borrow_rom_header(&emu.rom_header);
 
-        emu
-    }
-
-    // Render screen
-    pub fn render<W: Window>(&mut self, args: &RenderArgs, window: &mut PistonWindow<W>, framebuffer: &mut Texture<R>, evt: &Event) {
-        self.mem.gpu.display(window, evt);
+        Ok(emu)
     }
 
     // Update state
     // Gets called once a frame
-    pub fn update(&mut self, args: &UpdateArgs) {
+    pub fn update(&mut self, output: &mut FrameOutput) {
 
         // If is_stepping is false, runs for a frame (~70k clock cycles)
         // If it's true runs for just 1 instruction
 
         while self.frame_cycles < SCREEN_REFRESH_INTERVAL {
+            // The CPU runs the other components as it goes
             let cycles = self.cpu.exec(&mut self.mem);
-            self.mem.timer.step(cycles, &mut self.mem.if_);
-            self.mem.gpu.step(cycles, &mut self.mem.if_);
-
-            self.frame_cycles += cycles;
-
-            if self.cpu.get_regs().stop {self.cpu.stop(); return; }
+            // In double speed mode, only the CPU and the timer run faster
+            let clocks = if self.mem.double_speed {cycles / 2} else {cycles};
+
+            self.frame_cycles += clocks;
+            self.clock_cycles = self.clock_cycles.wrapping_add(clocks);
+
+            // STOP does the speed switch if KEY1 armed it, and pauses otherwise
+            if self.cpu.get_regs().stop {
+                if self.mem.switch_speed() {
+                    self.cpu.get_regs_mut().stop = false;
+                } else {
+                    self.cpu.stop();
+                    return;
+                }
+            }
             if self.is_instr_stepping { self.set_running(false) }; // kinda broken
         }
         if self.frame_cycles >= SCREEN_REFRESH_INTERVAL {
@@ -88,11 +141,160 @@ impl Emulator {
         if self.is_frame_stepping { self.set_running(false) };
         // Update gpu image data
         self.mem.gpu.update();
+        for event in self.mem.gpu.drain_events() {
+            output.gpu_event(event);
+        }
+        output.frame(&*self.mem.gpu.image_data);
+        output.audio(self.mem.apu.samples());
+        if let Err(why) = self.record_wav() {
+            error!("Couldn't write WAV file, recording stopped: {}", why);
+            self.wav = None;
+        }
+        self.mem.apu.clear_samples();
+
+        if self.frame_count % SAVE_INTERVAL == 0 {
+            if let Err(why) = self.flush_save_file() {
+                warn!("Couldn't write save file: {}", why);
+            }
+        }
+    }
+
+    // Keeps battery backed RAM in the given file. Loads it now if it exists,
+    // flush_save_file() writes to it. Does nothing for cartridges without a battery.
+    pub fn load_save_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        if !self.rom_header.has_battery() { return Ok(()) }
+
+        self.save_path = Some(path.as_ref().to_path_buf());
+        if !path.as_ref().exists() { return Ok(()) }
+
+        let data = try!(open_rom(&path));
+        self.mem.get_mapper_mut().load_battery(&data);
+        self.last_save = data;
+        info!("Loaded {} bytes from save file: {}", self.last_save.len(), path.as_ref().display());
+        Ok(())
     }
 
-    fn read_header(&mut self) {
-        self.rom_header = read_header_impl(&self);
+    // Writes battery backed RAM to the save file, if it changed since the last write
+    pub fn flush_save_file(&mut self) -> io::Result<()> {
+        let path = match self.save_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let data = self.mem.get_mapper().save_battery();
+        if data.is_empty() || data == self.last_save { return Ok(()) }
+
+        let mut file = try!(File::create(&path));
+        try!(file.write_all(&data));
+        debug!("Wrote {} bytes to save file: {}", data.len(), path.display());
+        self.last_save = data;
+        Ok(())
+    }
+
+    // Starts writing the APU's output to a WAV file, replacing any running
+    // recording
+    pub fn start_wav_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        try!(self.stop_wav_recording());
+        let file = BufWriter::new(try!(File::create(&path)));
+        self.wav = Some(try!(WavRecorder::new(file, self.mem.apu.get_sample_rate(), self.clock_cycles)));
+        info!("Recording audio to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    pub fn stop_wav_recording(&mut self) -> io::Result<()> {
+        match self.wav.take() {
+            Some(mut wav) => wav.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_wav(&self) -> bool {
+        self.wav.is_some()
+    }
+
+    fn record_wav(&mut self) -> io::Result<()> {
+        match self.wav {
+            Some(ref mut wav) => wav.record(self.mem.apu.samples(), self.clock_cycles),
+            None => Ok(()),
+        }
+    }
+
+    // Colors the DMG's shades are shown in, separately for the BG and the two
+    // sprite palettes
+    pub fn set_color_scheme(&mut self, scheme: ColorScheme) {
+        self.mem.gpu.set_color_scheme(scheme);
+    }
+
+    pub fn get_color_scheme(&self) -> ColorScheme {
+        self.mem.gpu.get_color_scheme()
+    }
+
+    // Switches to the next built in color scheme and returns its name
+    pub fn next_color_scheme(&mut self) -> &'static str {
+        self.scheme_index = (self.scheme_index + 1) % palette::SCHEMES.len();
+        let (name, scheme) = palette::SCHEMES[self.scheme_index];
+        self.set_color_scheme(scheme);
+        name
+    }
+
+    // Uses the colors from a palette file, see ColorScheme::parse() for the format
+    pub fn load_palette_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut text = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut text));
+        self.set_color_scheme(try!(ColorScheme::parse(&text)));
+        info!("Loaded palette from {}", path.as_ref().display());
+        Ok(())
+    }
+
+    // Snapshot of the whole machine, see the state module for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        state::write_header(&mut w);
+        w.u16(self.rom_header.get_global_checksum());
+        self.cpu.save_state(&mut w);
+        self.mem.save_state(&mut w);
+        w.into_inner()
+    }
+
+    // Restores a snapshot made by save_state() with the same ROM. If it can't
+    // be loaded, the emulator is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let backup = self.save_state();
+        let result = self.load_state_impl(data);
+        if result.is_err() {
+            self.load_state_impl(&backup).unwrap();
+        }
+        result
     }
+
+    fn load_state_impl(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut r = StateReader::new(data);
+        try!(r.header());
+        if try!(r.u16()) != self.rom_header.get_global_checksum() {
+            return Err(state::invalid_data("Save state is for a different ROM"));
+        }
+        try!(self.cpu.load_state(&mut r));
+        try!(self.mem.load_state(&mut r));
+        if !r.is_done() {
+            return Err(state::invalid_data("Save state has trailing data"));
+        }
+        Ok(())
+    }
+
+    pub fn save_state_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(&path));
+        try!(file.write_all(&self.save_state()));
+        info!("Saved state to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    pub fn load_state_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = try!(open_rom(&path));
+        try!(self.load_state(&data));
+        info!("Loaded state from {}", path.as_ref().display());
+        Ok(())
+    }
+
     pub fn get_header(&self) -> &CartridgeHeader {
         &self.rom_header
     }
@@ -141,6 +343,16 @@ fn open_rom<P: AsRef<Path>>(rom_path: P) -> io::Result< Vec<u8> > {
     return Ok(rom_buffer);
 }
 
+// Save file for a rom, next to it with a .sav extension
+pub fn save_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("sav")
+}
+
+// Save state file for a rom and a slot (0-9), next to it
+pub fn state_path<P: AsRef<Path>>(rom_path: P, slot: u8) -> PathBuf {
+    rom_path.as_ref().with_extension(format!("ss{}", slot))
+}
+
 // Wrapper for open_rom
 pub fn try_open_rom<P: AsRef<Path>>(rom_path: P) -> Vec<u8> {
 
@@ -168,4 +380,162 @@ pub fn try_open_rom<P: AsRef<Path>>(rom_path: P) -> Vec<u8> {
 mod emu_tests {
     use super::*;
 
-}
\ No newline at end of file
+    struct CountingOutput {
+        frames: u32,
+    }
+
+    impl FrameOutput for CountingOutput {
+        fn frame(&mut self, data: &ScreenData) {
+            self.frames += 1;
+        }
+    }
+
+    #[test]
+    fn headless_frame() {
+        // 32KB of NOPs, ROM only cartridge
+        let mut emu = Emulator::new(vec![0u8; 0x8000]).unwrap();
+        let mut output = CountingOutput { frames: 0 };
+
+        emu.update(&mut output);
+        emu.update(&mut output);
+
+        assert_eq!(output.frames, 2);
+        assert_eq!(emu.frame_count, 2);
+    }
+
+    #[test]
+    fn rom_too_small() {
+        let err = Emulator::new(vec![0u8; 0x100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn battery_save_file() {
+        let path = ::std::env::temp_dir().join("rustboy_battery_save_file.sav");
+        let _ = ::std::fs::remove_file(&path);
+
+        // MBC1+RAM+BATTERY with 8KB of RAM
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+
+        let mut emu = Emulator::new(rom.clone()).unwrap();
+        emu.load_save_file(&path).unwrap();
+        emu.mem.wb(0x0000, 0x0A);
+        emu.mem.wb(0xA010, 0x77);
+        emu.flush_save_file().unwrap();
+
+        let mut emu = Emulator::new(rom).unwrap();
+        emu.load_save_file(&path).unwrap();
+        emu.mem.wb(0x0000, 0x0A);
+        assert_eq!(emu.mem.rb(0xA010), 0x77);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        // Loop of INC A; JR -3 so the state keeps changing
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100] = 0x3C;
+        rom[0x101] = 0x18;
+        rom[0x102] = 0xFD;
+
+        let mut emu = Emulator::new(rom).unwrap();
+        emu.update(&mut NullOutput);
+        emu.mem.wb(0xC123, 0x45);
+        let state = emu.save_state();
+        let a = emu.cpu.get_regs().af();
+
+        emu.update(&mut NullOutput);
+        emu.mem.wb(0xC123, 0x00);
+        assert!(emu.cpu.get_regs().af() != a);
+
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.cpu.get_regs().af(), a);
+        assert_eq!(emu.mem.rb(0xC123), 0x45);
+        assert_eq!(emu.save_state(), state);
+
+        // Bad states leave the emulator untouched
+        assert!(emu.load_state(&state[..state.len() - 1]).is_err());
+        assert!(emu.load_state(b"nope").is_err());
+        assert_eq!(emu.save_state(), state);
+    }
+
+    #[test]
+    fn cgb_speed_switch() {
+        // CGB cartridge running LD A,1; LDH (4D),A; STOP; JR -2
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        rom[0x143] = 0x80;
+
+        let mut emu = Emulator::new(rom).unwrap();
+        assert!(emu.mem.is_cgb());
+        assert_eq!(emu.cpu.get_regs().af() >> 8, 0x11);
+
+        let cycles = emu.cpu.total_cycles;
+        emu.update(&mut NullOutput);
+        assert!(emu.mem.double_speed);
+        assert!(emu.is_running());
+        // Apart from the first few instructions, the CPU ran twice as many
+        // cycles as a frame has
+        assert!(emu.cpu.total_cycles - cycles > 2 * SCREEN_REFRESH_INTERVAL - 32);
+    }
+
+    #[test]
+    fn wav_recording() {
+        let path = ::std::env::temp_dir().join("rustboy_wav_recording.wav");
+
+        let mut emu = Emulator::new(vec![0u8; 0x8000]).unwrap();
+        emu.start_wav_recording(&path).unwrap();
+        let start = emu.clock_cycles;
+        for _ in 0..10 {
+            emu.update(&mut NullOutput);
+        }
+        let cycles = (emu.clock_cycles - start) as u64;
+        emu.stop_wav_recording().unwrap();
+        assert!(!emu.is_recording_wav());
+
+        let data = open_rom(&path).unwrap();
+        let samples = cycles * emu.mem.apu.get_sample_rate() as u64 / ::apu::CLOCK_SPEED as u64;
+        assert_eq!(data.len() as u64, 44 + samples * 4);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    // Runs a ROM from testroms/ until it prints its result through serial,
+    // blargg's ROMs end it with "Passed" or "Failed"
+    fn run_test_rom(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testroms").join(name);
+        let mut emu = Emulator::new(try_open_rom(&path)).unwrap();
+
+        for _ in 0..1000 {
+            emu.update(&mut NullOutput);
+            let out = String::from_utf8_lossy(emu.mem.serial_output()).into_owned();
+            if out.contains("Passed") || out.contains("Failed") {
+                return out;
+            }
+        }
+        String::from_utf8_lossy(emu.mem.serial_output()).into_owned()
+    }
+
+    #[test]
+    fn instr_timing_rom() {
+        let out = run_test_rom("instr_timing.gb");
+        assert!(out.contains("Passed"), "{}", out);
+    }
+
+    #[test]
+    fn memory_timing_roms() {
+        for name in &["mem_timing.gb", "01-read_timing.gb", "02-write_timing.gb", "03-modify_timing.gb"] {
+            let out = run_test_rom(name);
+            assert!(out.contains("Passed"), "{}: {}", name, out);
+        }
+    }
+
+    #[test]
+    fn interrupt_test_rom() {
+        let out = run_test_rom("02-interrupts.gb");
+        assert!(out.contains("Passed"), "{}", out);
+    }
+}
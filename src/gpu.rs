@@ -3,24 +3,36 @@
 //
 #[allow(dead_code)]
 
-use cpu::Interrupt;
+use std::{io, mem};
+use std::collections::VecDeque;
 
-use piston::input;
-use piston_window::*;
-use graphics::types::SourceRectangle;
+use cpu::Interrupt;
+use state::{self, StateWriter, StateReader};
+pub use palette::{Color, Palette, ColorScheme};
 
 const VRAM_SIZE: usize = 0x2000;
-pub const OAM_SIZE: usize = 0x9F;   // 0xfe00 - 0xfe9f is OAM
+pub const OAM_SIZE: usize = 0xA0;   // 0xfe00 - 0xfe9f is OAM
 const OAM_ENTRY_SIZE: usize = 4;
 const OBJ_COUNT: usize =  40;    // sprite count
-const NUM_TILES: usize = 192;       // number of in-memory tiles
+const OBJ_PER_LINE: usize = 10;  // sprites drawn on a single line at most
+const NUM_TILES: usize = 384;       // number of in-memory tiles, all of 8000-97FF
+const VRAM_BANKS: usize = 2;        // 1 on the DMG
+const CRAM_SIZE: usize = 64;        // CGB palette RAM, 8 palettes of 4 RGB555 colors
+
+// Steps of the BG/window fetcher that do something, the others just wait
+const FETCH_TILE: u8 = 1;   // tile number
+const FETCH_DATA: u8 = 5;   // tile row, once both bytes are read
+const FETCH_PUSH: u8 = 6;   // waits for the FIFO to be empty
+const OBJ_FETCH_DOTS: u8 = 6;   // dots taken by a sprite fetch
 
 pub const HEIGHT: usize = 144;
 pub const WIDTH: usize = 160;
 
+// Dimensions of the image produced by tile_dump(), 16x24 tiles
+pub const TILE_DUMP_WIDTH: usize = 16 * 8;
+pub const TILE_DUMP_HEIGHT: usize = 24 * 8;
+
 pub type ScreenData = [u8; WIDTH * HEIGHT * 4];
-pub type Color = [u8; 4];
-pub type Palette = [Color; 4];
 
 struct Palettes {
     bg: Palette,
@@ -28,31 +40,55 @@ struct Palettes {
     obp1: Palette,
 }
 
-const PALETTE_BW: Palette = [
-    [255, 255, 255, 255],
-    [148, 148, 148, 255],
-    [ 86,  86,  86, 255],
-    [  0,   0,   0, 255],
-];
-const PALETTE_GREEN: Palette = [
-    [225, 247, 207, 255],
-    [136, 193, 107, 255],
-    [ 49,  106, 74, 255],
-    [ 7,  24, 31, 255],
-];
-const PALETTE_PUKE_GREEN: Palette = [
-    [157, 188, 7, 255],
-    [122, 156, 107, 255],
-    [ 53,  99, 56, 255],
-    [ 13,  58, 8, 255],
-];
-// TODO: Switch palettes at runtome
-const PALETTE: &'static Palette = &PALETTE_GREEN;
+// Compiled CGB palettes, from palette RAM
+struct CgbPalettes {
+    bg: [Palette; 8],
+    obj: [Palette; 8],
+}
 
+// Tiles of both VRAM banks, bank 1's come after bank 0's
 struct Tiles {
-    data: [[[u8; 8]; 8]; NUM_TILES],
+    data: [[[u8; 8]; 8]; NUM_TILES * VRAM_BANKS],
     need_update: bool,
-    to_update: [bool; NUM_TILES],
+    to_update: [bool; NUM_TILES * VRAM_BANKS],
+}
+
+// Fetches rows of BG or window tiles for the pixel FIFO
+#[derive(Default)]
+struct Fetcher {
+    step: u8,
+    tile_x: u8,         // tile column, from SCX or the window's left edge
+    tile: usize,        // index into the tile cache
+    y: usize,           // row inside the tile
+    attr: u8,           // CGB map attributes
+    row: [u8; 8],
+    is_window: bool,
+    is_first: bool,
+}
+
+// A pixel in the BG FIFO
+#[derive(Default, Copy, Clone)]
+struct BgPixel {
+    color: u8,
+    palette: u8,        // CGB palette
+    priority: bool,     // CGB, drawn over sprites
+}
+
+// A pixel in the sprite FIFO
+#[derive(Default, Copy, Clone)]
+struct ObjPixel {
+    color: u8,          // 0 is transparent
+    obp1: bool,
+    palette: u8,        // CGB palette
+    behind_bg: bool,
+    index: usize,       // in OAM, for CGB priority
+}
+
+// Changes to the LCD the frontend might want to know about
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum GpuEvent {
+    LcdOff,     // The screen went blank
+    LcdOn,      // Drawing starts again, after a blank frame
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
@@ -76,9 +112,11 @@ pub struct Gpu {
 
     pub clock: u32,
 
-    pub vrambank: Box<[u8; VRAM_SIZE]>,
+    // Both VRAM banks, bank 1 after bank 0. Bank 1 is CGB only, it has more
+    // tiles and the attributes of the BG maps.
+    pub vrambank: Box<[u8; VRAM_SIZE * VRAM_BANKS]>,
 
-    // Selects vrambank (only 0 supported since we don't do CGB)
+    // Selects vrambank (always 0 on the DMG)
     vrambank_sel: u8,
 
     // 0xff40 - LCD control (LCDC) - in order from most to least significant bit
@@ -97,6 +135,11 @@ pub struct Gpu {
     mode1int: bool, // Mode 1 V-Blank Interrupt     (1=Enable)
     mode0int: bool, // Mode 0 H-Blank Interrupt     (1=Enable)
 
+    // The STAT interrupt line: all of the enabled sources above OR-ed
+    // together. The interrupt is only requested when it goes from low to high,
+    // so one source can block another while it's active.
+    stat_line: bool,
+
     // 0xff42 - SCY - Scroll Y
     scy: u8,
     // 0xff43 - SCX - Scroll X
@@ -134,20 +177,55 @@ pub struct Gpu {
     // 0xff4b - WX - Window X Position minus 7
     wx: u8,
 
-    // Compiled palettes. These are updated when writing to BGP/OBP0/OBP1. Meant
-    // for non CGB use only. Each palette is an array of 4 color schemes. Each
-    // color scheme is one in PALETTE.
+    // Internal window line counter. Only advances on lines the window was
+    // drawn on, so hiding the window for a few lines doesn't skip any of it.
+    window_line: u8,
+    // Whether LY matched WY at some point in this frame. The window doesn't
+    // show up before that.
+    wy_triggered: bool,
+
+    // Mode 3. Pixels are shifted out of the FIFO one per dot, so register
+    // writes in the middle of a line take effect from the next pixel on.
+    fetcher: Fetcher,
+    bg_fifo: VecDeque<BgPixel>,
+    obj_fifo: VecDeque<ObjPixel>,
+    lx: u8,                         // X of the next pixel shifted out
+    discard: u8,                    // pixels to drop before drawing any
+    line_sprites: Vec<usize>,       // sprites on this line not fetched yet
+    obj_fetch: Option<usize>,       // sprite being fetched
+    obj_fetch_dots: u8,
+
+    // The first frame after the LCD is turned on isn't shown
+    blank_frame: bool,
+
+    events: Vec<GpuEvent>,
+
+    // Set when a visible line enters HBlank, the CGB's HBlank DMA copies a
+    // block then. Cleared by whoever handles it.
+    pub hblank_started: bool,
+
+    // The colors shades are shown in
+    scheme: ColorScheme,
+
+    // Compiled palettes. These are updated when writing to BGP/OBP0/OBP1 or
+    // changing the color scheme. Meant for non CGB use only. Each palette is
+    // an array of 4 colors from the scheme's palette for that layer.
     pal: Box<Palettes>,
 
+    // 0xff68-0xff6b - CGB palette RAM. BCPS/OCPS select a byte (bits 0-5) and
+    // whether writing to it moves on to the next one (bit 7).
+    bg_cram: [u8; CRAM_SIZE],
+    obj_cram: [u8; CRAM_SIZE],
+    bcps: u8,
+    ocps: u8,
+    cgb_pal: Box<CgbPalettes>,
+
     // Compiled tiles
     tiles: Box<Tiles>,
-
-    // Image for drawing
-    pub img: Image,
 }
 
 impl Gpu {
-    pub fn new<W: Window>(window: &PistonWindow<W>) -> Gpu {
+    pub fn new() -> Gpu {
         let mut gpu: Gpu = Gpu {
             image_data: Box::new([255; HEIGHT * WIDTH * 4]),
             oam: [0; OAM_SIZE],
@@ -157,13 +235,26 @@ impl Gpu {
             is_sgb: false,
 
             clock: 0,
-            vrambank: Box::new([0; VRAM_SIZE]),
+            vrambank: Box::new([0; VRAM_SIZE * VRAM_BANKS]),
             vrambank_sel: 0,
 
             mode: Mode::RdOam,
             wx: 0, wy: 0, obp1: 0, obp0: 0, bgp: 0,
+            window_line: 0,
+            wy_triggered: false,
+            fetcher: Fetcher::default(),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            lx: 0, discard: 0,
+            line_sprites: Vec::with_capacity(OBJ_PER_LINE),
+            obj_fetch: None, obj_fetch_dots: 0,
+            blank_frame: false,
+            events: Vec::new(),
+            hblank_started: false,
+            scheme: ColorScheme::default(),
             lyc: 0, ly: 0, scx: 0, scy: 0,
             mode0int: false, mode1int: false, mode2int: false, lycly: false,
+            stat_line: false,
             bgon: false, objon: false, objsize: false, bgmap: false,
             tiledata: false,
             winon: false, winmap: false, lcdon: false,
@@ -174,16 +265,21 @@ impl Gpu {
                 obp1: [[0; 4]; 4],
             }),
 
+            // Palette RAM starts out white
+            bg_cram: [0xFF; CRAM_SIZE],
+            obj_cram: [0xFF; CRAM_SIZE],
+            bcps: 0,
+            ocps: 0,
+            cgb_pal: Box::new(CgbPalettes {
+                bg: [[[255; 4]; 4]; 8],
+                obj: [[[255; 4]; 4]; 8],
+            }),
+
             tiles: Box::new(Tiles {
                 need_update: true,  // Does this need to be true?
-                to_update: [true;  NUM_TILES],
-                data: [[[0; 8]; 8]; NUM_TILES],
+                to_update: [true;  NUM_TILES * VRAM_BANKS],
+                data: [[[0; 8]; 8]; NUM_TILES * VRAM_BANKS],
             }),
-
-            img: {
-                let r: SourceRectangle = [0.0, 0.0, ::SCREEN_DIMS[0] as f64, ::SCREEN_DIMS[1] as f64];
-                Image::new().src_rect(r)
-            }
         };
 
         for i in 0..HEIGHT * WIDTH * 4 {
@@ -191,9 +287,9 @@ impl Gpu {
         }
 
         // Is this needed?
-        update_pal(&mut gpu.pal.bg, 0xE4);
-        update_pal(&mut gpu.pal.obp0, 0xE4);
-        update_pal(&mut gpu.pal.obp1, 0xE4);
+        update_pal(&mut gpu.pal.bg, 0xE4, &gpu.scheme.bg);
+        update_pal(&mut gpu.pal.obp0, 0xE4, &gpu.scheme.obj0);
+        update_pal(&mut gpu.pal.obp1, 0xE4, &gpu.scheme.obj1);
 
         // BIOS SKIP
         gpu.clock = 0xABCC % 456;
@@ -206,23 +302,6 @@ impl Gpu {
         gpu
     }
 
-    pub fn display<W: Window>(&mut self, window: &mut PistonWindow<W>, evt: &input::Event) {
-        //self.update();
-
-        // window.draw_2d(&evt, |c, g| {
-        //     self.img.draw(&framebuffer, &c.draw_state, c.transform, g);
-        // })
-        //framebuffer = from_memory(&mut window.factory, &*emu.mem.gpu.image_data, 160, 144, &ts).unwrap();
-
-        // window.draw_2d(evt, |c, g| {
-        //     for y in 0..HEIGHT {
-        //         for x in 0..WIDTH {
-
-        //         }
-        //     }
-        // });
-    }
-
     pub fn update(&mut self) {
 
         // Debug code
@@ -240,9 +319,89 @@ impl Gpu {
         // self.clock += 1;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.oam);
+        w.bytes(&*self.vrambank);
+        w.u8(self.vrambank_sel);
+        w.bytes(&*self.image_data);
+
+        // LY is written as the line number, rb() gives 0 for most of line 153
+        for addr in 0xFF40..0xFF4C {
+            w.u8(if addr == 0xFF44 {self.ly} else {self.rb(addr)});
+        }
+        w.u8(self.mode as u8);
+        w.u32(self.clock);
+        w.u8(self.window_line);
+        w.bool(self.wy_triggered);
+        w.bool(self.blank_frame);
+
+        w.bytes(&self.bg_cram);
+        w.bytes(&self.obj_cram);
+        w.u8(self.bcps);
+        w.u8(self.ocps);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        try!(r.bytes(&mut self.oam));
+        try!(r.bytes(&mut *self.vrambank));
+        self.vrambank_sel = try!(r.u8());
+        try!(r.bytes(&mut *self.image_data));
+
+        // Registers go through wb() to update the palettes. LY is read-only
+        // there so it's set directly. The LCD is already in the right state
+        // so writing LCDC doesn't turn it on or off.
+        let mut regs = [0u8; 12];
+        try!(r.bytes(&mut regs));
+        self.lcdon = regs[0] & 0x80 != 0;
+        for (addr, &val) in (0xFF40..0xFF4C).zip(regs.iter()) {
+            self.wb(addr, val);
+        }
+        self.ly = regs[4];
+
+        self.mode = match try!(r.u8()) {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::RdOam,
+            3 => Mode::RdVram,
+            _ => return Err(state::invalid_data("Invalid GPU mode")),
+        };
+        self.clock = try!(r.u32());
+        self.window_line = try!(r.u8());
+        self.wy_triggered = try!(r.bool());
+        self.blank_frame = try!(r.bool());
+
+        try!(r.bytes(&mut self.bg_cram));
+        try!(r.bytes(&mut self.obj_cram));
+        self.bcps = try!(r.u8());
+        self.ocps = try!(r.u8());
+        for i in 0..CRAM_SIZE / 2 {
+            compile_cgb_color(&self.bg_cram, &mut self.cgb_pal.bg, i * 2);
+            compile_cgb_color(&self.obj_cram, &mut self.cgb_pal.obj, i * 2);
+        }
+
+        // The pixel FIFO isn't saved, a state made in mode 3 skips the rest
+        // of that line
+        if self.mode == Mode::RdVram {
+            self.mode = Mode::HBlank;
+        }
+
+        // Only picks up the STAT line's level, loading doesn't interrupt
+        self.update_stat_line(&mut 0);
+
+        // Recompile every tile from the new VRAM
+        self.tiles.to_update = [true; NUM_TILES * VRAM_BANKS];
+        self.tiles.need_update = true;
+        Ok(())
+    }
+
+    // Start of the selected VRAM bank
+    fn vram_offset(&self) -> usize {
+        self.vrambank_sel as usize * VRAM_SIZE
+    }
+
     pub fn rb_vram(&self, addr: u16) -> u8 {
         match addr {
-            0x8000 ... 0x9FFF => self.vrambank[addr as usize - 0x8000],
+            0x8000 ... 0x9FFF => self.vrambank[self.vram_offset() + addr as usize - 0x8000],
             //0xA000 ... 0xBFFF => self.vrambanks[1][addr as usize - 0xA000],
             _ => unreachable!()
         }
@@ -252,23 +411,14 @@ impl Gpu {
         match addr {
             0x8000 ... 0x9FFF => {
                 //trace!("writing to VRAM1 {:04X}  data {:02X}", addr - 0x8000, data);
-                let mut tilei: u16;
-
-                tilei = (addr - 0x8000 as u16) / 16;
-
-                if tilei < NUM_TILES as u16 {
-                    self.tiles.to_update[tilei as usize] = true;
+                // Tile data is 8000-97FF, the rest are the tile maps
+                let tilei = (addr - 0x8000) as usize / 16;
+                if tilei < NUM_TILES {
+                    self.tiles.to_update[self.vrambank_sel as usize * NUM_TILES + tilei] = true;
                     self.tiles.need_update = true;
                 }
-
-                if !self.tiledata && addr >= 0x8800 {
-                    tilei = (addr - 0x8800 as u16) / 16;
-                    if tilei < NUM_TILES as u16 {
-                        self.tiles.to_update[tilei as usize] = true;
-                        self.tiles.need_update = true;
-                    }
-                }
-                self.vrambank[addr as usize - 0x8000] = data;
+                let offset = self.vram_offset();
+                self.vrambank[offset + addr as usize - 0x8000] = data;
             },
             // 0xA000 ... 0xBFFF => {
             //    //trace!("writing to VRAM2 {:04X}  data {:02X}", addr - 0xA000 , data);
@@ -293,17 +443,19 @@ impl Gpu {
             }
 
             0x41 => {
+                // bit7 is unused and always reads 1
+                0x80 |
                 ((self.lycly as u8)                                   << 6) |
                 ((self.mode2int as u8)                                << 5) |
                 ((self.mode1int as u8)                                << 4) |
                 ((self.mode0int as u8)                                << 3) |
-                ((if self.lycly as u8 == self.ly {1} else {0} as u8) << 2) |
+                ((self.lyc_match() as u8)                             << 2) |
                 ((self.mode as u8)                                    << 0)
             }
 
             0x42 => self.scy,
             0x43 => self.scx,
-            0x44 => self.ly,
+            0x44 => self.ly_reg(),
             0x45 => self.lyc,
             // 0x46 is DMA transfer, can't read
             0x47 => self.bgp,
@@ -311,7 +463,11 @@ impl Gpu {
             0x49 => self.obp1,
             0x4a => self.wy,
             0x4b => self.wx,
-            0x4f => self.vrambank_sel,
+            0x4f if self.is_cgb => 0xFE | self.vrambank_sel,
+            0x68 if self.is_cgb => 0x40 | self.bcps,
+            0x69 if self.is_cgb => self.bg_cram[(self.bcps & 0x3F) as usize],
+            0x6a if self.is_cgb => 0x40 | self.ocps,
+            0x6b if self.is_cgb => self.obj_cram[(self.ocps & 0x3F) as usize],
 
             _ => 0xff
         }
@@ -333,6 +489,13 @@ impl Gpu {
                 if !before && self.lcdon {
                     self.clock = 4; // ??? why 4?!
                     self.ly = 0;
+                    self.window_line = 0;
+                    self.wy_triggered = false;
+                    self.blank_frame = true;
+                    self.events.push(GpuEvent::LcdOn);
+                }
+                if before && !self.lcdon {
+                    self.lcd_off();
                 }
             }
 
@@ -349,12 +512,24 @@ impl Gpu {
             0x43 => { self.scx = val; }
             // 0x44 self.ly is read-only
             0x45 => { self.lyc = val; }
-            0x47 => { self.bgp = val; update_pal(&mut self.pal.bg, val); }
-            0x48 => { self.obp0 = val; update_pal(&mut self.pal.obp0, val); }
-            0x49 => { self.obp1 = val; update_pal(&mut self.pal.obp1, val); }
+            0x47 => { self.bgp = val; update_pal(&mut self.pal.bg, val, &self.scheme.bg); }
+            0x48 => { self.obp0 = val; update_pal(&mut self.pal.obp0, val, &self.scheme.obj0); }
+            0x49 => { self.obp1 = val; update_pal(&mut self.pal.obp1, val, &self.scheme.obj1); }
             0x4a => { self.wy = val; }
             0x4b => { self.wx = val; }
             0x4f => { if self.is_cgb { self.vrambank_sel = val & 1; } }
+            0x68 => { self.bcps = val & 0xBF; }
+            0x69 => {
+                if self.is_cgb {
+                    write_cram(&mut self.bg_cram, &mut self.bcps, &mut self.cgb_pal.bg, val);
+                }
+            }
+            0x6a => { self.ocps = val & 0xBF; }
+            0x6b => {
+                if self.is_cgb {
+                    write_cram(&mut self.obj_cram, &mut self.ocps, &mut self.cgb_pal.obj, val);
+                }
+            }
 
             _ => {}
         }
@@ -364,14 +539,81 @@ impl Gpu {
     // synchronized with the CPU clock because in a real GB, the two are
     // matched up on the same clock.
     //
-    // This function mostly doesn't do anything except for incrementing its own
-    // internal counter of clock cycles that have passed. It's a state machine
-    // between a few different states. In one state, however, the rendering of a
-    // screen occurs, but that doesn't always happen when calling this function.
+    // The GPU runs dot by dot (one dot per clock cycle). Each line is 456 dots:
+    // 80 for the OAM scan, then mode 3 until the pixel FIFO has shifted out the
+    // whole line, which takes longer with SCX, the window or sprites, and hblank
+    // for the rest.
     pub fn step(&mut self, clocks: u32, if_: &mut u8) {
         // Timings located here:
         //      http://http://problemkaputt.de//pandocs.htm#lcdstatusregister
-        self.clock += clocks;
+        if !self.lcdon { return }
+
+        for _ in 0..clocks {
+            self.dot(if_);
+            self.update_stat_line(if_);
+        }
+    }
+
+    // With the LCD off the GPU stands still at the start of line 0, reporting
+    // mode 0, and the screen is blank
+    fn lcd_off(&mut self) {
+        self.ly = 0;
+        self.clock = 0;
+        self.mode = Mode::HBlank;
+        self.stat_line = false;
+        for pixel in self.image_data.chunks_mut(4) {
+            pixel.copy_from_slice(&self.scheme.bg[0]);
+        }
+        self.events.push(GpuEvent::LcdOff);
+    }
+
+    pub fn get_color_scheme(&self) -> ColorScheme {
+        self.scheme
+    }
+
+    // Takes effect from the next pixel drawn
+    pub fn set_color_scheme(&mut self, scheme: ColorScheme) {
+        self.scheme = scheme;
+        update_pal(&mut self.pal.bg, self.bgp, &scheme.bg);
+        update_pal(&mut self.pal.obp0, self.obp0, &scheme.obj0);
+        update_pal(&mut self.pal.obp1, self.obp1, &scheme.obj1);
+    }
+
+    // Mode 0, which is also what the GPU reports with the LCD off
+    pub fn is_hblank(&self) -> bool {
+        self.mode == Mode::HBlank
+    }
+
+    // Takes the events since the last call
+    pub fn drain_events(&mut self) -> Vec<GpuEvent> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+
+    // The value of the LY register. Line 153 only lasts a few dots as 153,
+    // LY reads 0 for the rest of it.
+    fn ly_reg(&self) -> u8 {
+        if self.ly == 153 && self.clock >= 4 {0} else {self.ly}
+    }
+
+    // LY and LYC are compared every dot, so writing LYC takes effect right away
+    fn lyc_match(&self) -> bool {
+        self.ly_reg() == self.lyc
+    }
+
+    fn update_stat_line(&mut self, if_: &mut u8) {
+        let line = (self.lycly && self.lyc_match()) ||
+                   (self.mode0int && self.mode == Mode::HBlank) ||
+                   (self.mode1int && self.mode == Mode::VBlank) ||
+                   (self.mode2int && self.mode == Mode::RdOam);
+
+        if line && !self.stat_line {
+            *if_ |= Interrupt::LCDStat as u8;
+        }
+        self.stat_line = line;
+    }
+
+    fn dot(&mut self, if_: &mut u8) {
+        self.clock += 1;
 
         // If clock >= 456, then we've completed an entire line. This line might
         // have been part of a vblank or part of a scanline.
@@ -379,25 +621,24 @@ impl Gpu {
             self.clock -= 456;
             self.ly = (self.ly + 1) % 154; // 144 lines tall, 10 for a vblank
 
-            // debug!("Completed an entire line");
-
             if self.ly >= 144 && self.mode != Mode::VBlank {
                 self.switch(Mode::VBlank, if_);
             }
-
-            if self.ly == self.lyc && self.lycly {
-                *if_ |= Interrupt::LCDStat as u8;
-            }
         }
 
         // Hop between modes if we're not in vblank
-        if self.ly < 144 {
-            if self.clock <= 80 { // RDOAM takes 80 cycles
-                if self.mode != Mode::RdOam { self.switch(Mode::RdOam, if_); }
-            } else if self.clock <= 252 { // RDVRAM takes 172 cycles
-                if self.mode != Mode::RdVram { self.switch(Mode::RdVram, if_); }
-            } else { // HBLANK takes rest of time before line rendered
-                if self.mode != Mode::HBlank { self.switch(Mode::HBlank, if_); }
+        if self.ly >= 144 { return }
+
+        if self.clock < 80 { // RDOAM takes 80 cycles
+            if self.mode != Mode::RdOam { self.switch(Mode::RdOam, if_); }
+        } else if self.mode == Mode::RdOam {
+            self.switch(Mode::RdVram, if_);
+        }
+
+        if self.mode == Mode::RdVram {
+            self.draw_dot();
+            if self.lx as usize == WIDTH {
+                self.switch(Mode::HBlank, if_);
             }
         }
     }
@@ -406,28 +647,297 @@ impl Gpu {
         self.mode = mode;
         match mode {
             Mode::HBlank => {
-                trace!("HBlank! Rendering...");
-                self.render_line();
-                if self.mode0int {
-                    *if_ |= Interrupt::LCDStat as u8;
+                if self.fetcher.is_window {
+                    self.window_line += 1;
                 }
+                self.hblank_started = true;
             }
             Mode::VBlank => {
                 // TODO: a frame is ready, it should be put on screen at this
                 // point
                 debug!("GPU: VBlank!");
+                self.window_line = 0;
+                self.wy_triggered = false;
+                self.blank_frame = false;
                 *if_ |= Interrupt::Vblank as u8;
-                if self.mode1int {
-                    *if_ |= Interrupt::LCDStat as u8;
+            }
+            Mode::RdOam => {}
+            Mode::RdVram => self.start_drawing(),
+        }
+    }
+
+    // Sets up mode 3 for a new line
+    fn start_drawing(&mut self) {
+        // The window only shows up once LY has matched WY in this frame
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+
+        // OAM scan. The first 10 sprites in OAM that land on this line get
+        // drawn, the rest are dropped. X doesn't matter here, sprites that are
+        // off screen horizontally still count towards the limit.
+        let line = self.ly as i32;
+        let ysize = if self.objsize {16} else {8};
+        self.line_sprites = (0..OBJ_COUNT)
+            .filter(|&i| {
+                let yoff = self.oam[i * OAM_ENTRY_SIZE] as i32 - 16;
+                yoff <= line && line < yoff + ysize
+            })
+            .take(OBJ_PER_LINE)
+            .collect();
+
+        // The first fetch is thrown away. Together with it, mode 3 takes at
+        // least 172 dots.
+        self.fetcher = Fetcher { is_first: true, step: FETCH_TILE + 1, ..Fetcher::default() };
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.obj_fetch = None;
+        self.lx = 0;
+
+        // The fine scroll is done by throwing away the first SCX % 8 pixels
+        self.discard = self.scx & 7;
+    }
+
+    // One dot of mode 3. The fetcher keeps the BG FIFO filled and a pixel is
+    // shifted out every dot, except while a sprite is being fetched or the
+    // window is starting.
+    fn draw_dot(&mut self) {
+        if self.obj_fetch.is_some() {
+            // The BG fetch in progress gets finished first
+            if self.fetcher.step < FETCH_PUSH {
+                self.fetch_dot();
+            } else {
+                self.obj_fetch_dots -= 1;
+                if self.obj_fetch_dots == 0 {
+                    let i = self.obj_fetch.take().unwrap();
+                    self.fetch_sprite(i);
+                }
+            }
+            return;
+        }
+
+        // Shifting goes before fetching, so the fetcher can push in the same
+        // dot the FIFO runs out and the pixels keep flowing
+        if !self.bg_fifo.is_empty() {
+            if self.window_starts() {
+                // The BG pixels are dropped and the fetcher starts over with
+                // the window's first tile, which costs 6 dots
+                self.bg_fifo.clear();
+                self.fetcher = Fetcher { is_window: true, step: FETCH_TILE, ..Fetcher::default() };
+                self.discard = 7u8.saturating_sub(self.wx);
+            } else if let Some(i) = self.next_sprite() {
+                self.obj_fetch = Some(i);
+                self.obj_fetch_dots = OBJ_FETCH_DOTS;
+            } else {
+                self.shift_pixel();
+            }
+        }
+        self.fetch_dot();
+    }
+
+    // Whether the window starts at the next pixel. Its top left corner is at
+    // (WX-7, WY). On the DMG, clearing the BG enable bit hides it too.
+    fn window_starts(&self) -> bool {
+        (self.bgon || self.is_cgb) && self.winon && self.wy_triggered && !self.fetcher.is_window &&
+            self.wx <= 166 && self.lx as u32 + 7 >= self.wx as u32
+    }
+
+    // Takes the next sprite to fetch off the OAM scan's list: the first one in
+    // OAM order whose left edge has been reached. Sprites partly off the left
+    // of the screen are fetched at the first pixel.
+    fn next_sprite(&mut self) -> Option<usize> {
+        if !self.objon || self.discard > 0 { return None }
+
+        let lx = self.lx as usize + 8;
+        let oam = &self.oam;
+        match self.line_sprites.iter().position(|&i| oam[i * OAM_ENTRY_SIZE + 1] as usize <= lx) {
+            Some(pos) => Some(self.line_sprites.remove(pos)),
+            None => None,
+        }
+    }
+
+    fn shift_pixel(&mut self) {
+        let bg = self.bg_fifo.pop_front().unwrap();
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = match self.obj_fifo.pop_front() {
+            // A color index of 0 for sprites means transparent
+            Some(obj) if obj.color != 0 && self.objon => Some(obj),
+            _ => None,
+        };
+
+        let (colori, pal) = if self.is_cgb {
+            self.cgb_pixel(bg, obj)
+        } else {
+            self.dmg_pixel(bg, obj)
+        };
+
+        if !self.blank_frame {
+            let coff = (self.ly as usize * WIDTH + self.lx as usize) * 4;
+            set_pixel_index(&mut self.image_data, coff, colori as usize, &pal);
+        }
+        self.lx += 1;
+    }
+
+    // Color index and palette of a pixel on the DMG
+    fn dmg_pixel(&self, bg: BgPixel, obj: Option<ObjPixel>) -> (u8, Palette) {
+        // Clearing the BG enable bit blanks the BG and window
+        let (bg, bgp) = if self.bgon {(bg.color, self.pal.bg)} else {(0, self.scheme.bg)};
+
+        match obj {
+            // bit7 of the flags puts the sprite behind BG colors 1-3
+            Some(obj) if !(obj.behind_bg && bg != 0) =>
+                (obj.color, if obj.obp1 {self.pal.obp1} else {self.pal.obp0}),
+            _ => (bg, bgp),
+        }
+    }
+
+    // Color index and palette of a pixel on the CGB. The BG enable bit turns
+    // off the BG's priority instead, sprites are drawn over it no matter what.
+    fn cgb_pixel(&self, bg: BgPixel, obj: Option<ObjPixel>) -> (u8, Palette) {
+        match obj {
+            Some(obj) if !self.bgon || bg.color == 0 || !(bg.priority || obj.behind_bg) =>
+                (obj.color, self.cgb_pal.obj[obj.palette as usize]),
+            _ => (bg.color, self.cgb_pal.bg[bg.palette as usize]),
+        }
+    }
+
+    // One dot of the BG/window fetcher. Reading the tile number, the low and
+    // the high byte of the tile's row take 2 dots each, then the row waits
+    // until the FIFO is empty to be pushed.
+    fn fetch_dot(&mut self) {
+        match self.fetcher.step {
+            FETCH_TILE => {
+                // Backgrounds wrap around, the window is never scrolled and
+                // uses its own line counter instead of LY
+                let (mapbase, x, y) = if self.fetcher.is_window {
+                    (self.winbase(), self.fetcher.tile_x as usize, self.window_line as usize)
+                } else {
+                    (self.bgbase(),
+                     (self.scx >> 3) as usize + self.fetcher.tile_x as usize,
+                     self.ly.wrapping_add(self.scy) as usize)
+                };
+
+                // Each map row is 32 tiles, each tile is 8 pixels high
+                let mapaddr = mapbase + ((y >> 3) << 5) + (x & 31);
+                let tilei = self.vrambank[mapaddr];
+                // tiledata = 0 => tilei is a signed byte, bg_tile() handles it
+                self.fetcher.tile = self.bg_tile(tilei);
+                self.fetcher.y = y & 7;
+
+                // On the CGB, the same spot in VRAM bank 1 has the tile's
+                // attributes. bit3 is the tile's VRAM bank, bit6 the vertical
+                // flip flag.
+                if self.is_cgb {
+                    let attr = self.vrambank[VRAM_SIZE + mapaddr];
+                    if attr & 0x08 != 0 {
+                        self.fetcher.tile += NUM_TILES;
+                    }
+                    if attr & 0x40 != 0 {
+                        self.fetcher.y = 7 - self.fetcher.y;
+                    }
+                    self.fetcher.attr = attr;
                 }
             }
-            Mode::RdOam => {
-                if self.mode2int {
-                    *if_ |= Interrupt::LCDStat as u8;
+            FETCH_DATA => {
+                let (tile, y) = (self.fetcher.tile, self.fetcher.y);
+                self.fetcher.row = self.tile_row(tile, y);
+                // bit5 is the horizontal flip flag
+                if self.fetcher.attr & 0x20 != 0 {
+                    self.fetcher.row.reverse();
                 }
             }
-            Mode::RdVram => {}
+            FETCH_PUSH => {
+                if !self.bg_fifo.is_empty() { return }
+
+                // The first fetch of a line is thrown away
+                if self.fetcher.is_first {
+                    self.fetcher.is_first = false;
+                } else {
+                    // bits 0-2 are the CGB palette, bit7 gives the BG priority
+                    // over sprites
+                    let attr = self.fetcher.attr;
+                    for &color in self.fetcher.row.iter() {
+                        self.bg_fifo.push_back(BgPixel {
+                            color: color,
+                            palette: attr & 0x07,
+                            priority: attr & 0x80 != 0,
+                        });
+                    }
+                    self.fetcher.tile_x += 1;
+                }
+                self.fetcher.step = 0;
+                return;
+            }
+            _ => {}
+        }
+        self.fetcher.step += 1;
+    }
+
+    // Mixes a sprite's row into the sprite FIFO. On the DMG, whatever is
+    // already there belongs to sprites with a higher priority (lower X, then
+    // lower OAM index), so only transparent pixels get replaced. On the CGB
+    // only the OAM index counts.
+    fn fetch_sprite(&mut self, i: usize) {
+        let mut sprite = [0u8; OAM_ENTRY_SIZE];
+        sprite.copy_from_slice(&self.oam[i * OAM_ENTRY_SIZE..(i + 1) * OAM_ENTRY_SIZE]);
+        let ysize = if self.objsize {16} else {8};
+        let mut tile = sprite[2] as usize;
+        let flags = sprite[3];
+
+        // bit6 is the vertical flip bit. It flips the whole sprite, so in
+        // 8x16 mode the two tiles swap places as well.
+        let mut y = self.ly as i32 - (sprite[0] as i32 - 16);
+        if flags & 0x40 != 0 {
+            y = ysize - 1 - y;
+        }
+
+        // 8x16 tiles always use adjacent tile indices, the lowest bit is
+        // ignored. Sprites always use 8000-8FFF, so the tile index is a raw
+        // index.
+        if ysize == 16 {
+            tile = (tile & 0xfe) | (y as usize >> 3);
+        }
+        // bit3 is the tile's VRAM bank on the CGB
+        if self.is_cgb && flags & 0x08 != 0 {
+            tile += NUM_TILES;
+        }
+        let row = self.tile_row(tile, (y & 7) as usize);
+
+        // bit4 is the palette number. 0 = obp0, 1 = obp1. bits 0-2 are the
+        // palette on the CGB.
+        let pixel = ObjPixel {
+            color: 0,
+            obp1: flags & 0x10 != 0,
+            palette: flags & 0x07,
+            behind_bg: flags & 0x80 != 0,
+            index: i,
+        };
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+
+        // Pixels left of the current one are off screen
+        let skip = self.lx as usize + 8 - sprite[1] as usize;
+        for x in skip..8 {
+            // bit5 is the horizontal flip flag
+            let colori = row[if flags & 0x20 != 0 {7 - x} else {x}];
+            let slot = &mut self.obj_fifo[x - skip];
+            if slot.color == 0 || (self.is_cgb && colori != 0 && i < slot.index) {
+                *slot = ObjPixel { color: colori, ..pixel };
+            }
+        }
+    }
+
+    // A row of a compiled tile, compiling the tiles that changed first
+    fn tile_row(&mut self, tile: usize, y: usize) -> [u8; 8] {
+        if self.tiles.need_update {
+            self.update_tileset();
+            self.tiles.need_update = false;
         }
+        self.tiles.data[tile][y]
     }
 
     fn update_tileset(&mut self) {
@@ -448,19 +958,12 @@ impl Gpu {
             //      byte 1 : 01101010
             //
             // The colors are [0, 2, 2, 1, 3, 0, 3, 1]
-            // println!("-- memory addr: {:#0X}   {:#0X}", ((i % NUM_TILES) * 16) + 0x8000, self.vrambank[((i % NUM_TILES) * 16)]);
             for j in 0..8 {
-                let addr = ((i % NUM_TILES) * 16) + j * 2;
-
-                // println!("memory addr: {:#0X}", addr + 0x8000);
                 // All tiles are located 0x8000-0x97ff => 0x0000-0x17ff in VRAM
-                // meaning that the index is simply an index into raw VRAM
-                let (mut lsb, mut msb) = if i < NUM_TILES {
-                    (self.vrambank[addr], self.vrambank[addr + 1])
-                } else {
-                    panic!("second VRAM bank used");
-                    //(self.vrambanks[1][addr], self.vrambanks[1][addr + 1])
-                };
+                // meaning that the index is simply an index into raw VRAM,
+                // after the bank's offset
+                let addr = (i / NUM_TILES) * VRAM_SIZE + (i % NUM_TILES) * 16 + j * 2;
+                let (mut lsb, mut msb) = (self.vrambank[addr], self.vrambank[addr + 1]);
 
                 // LSB is the right-most pixel.
                 for k in (0..8).rev() {
@@ -484,25 +987,16 @@ impl Gpu {
         if self.bgmap {0x1c00} else {0x1800}
     }
 
-    fn render_line(&mut self) {
-        if !self.lcdon { return }
-
-        let mut scanline = [0u8; WIDTH];
-
-        if self.tiles.need_update {
-            self.update_tileset();
-            self.tiles.need_update = false;
-        }
+    pub fn winbase(&self) -> usize {
+        // self.winmap: 0=9800-9bff, 1=9c00-9fff
+        if self.winmap {0x1c00} else {0x1800}
+    }
 
-        if self.bgon {
-            self.render_background(&mut scanline);
-        }
-        if self.winon {
-            //self.render_window(&mut scanline);
-        }
-        if self.objon {
-            self.render_sprites(&mut scanline);
-        }
+    // Index into the tile cache for a BG/window map entry. With tiledata = 1
+    // tiles 0-255 are at 8000-8FFF, otherwise tiles -128-127 are at 8800-97FF
+    // (tile 0 is at 9000)
+    fn bg_tile(&self, tilei: u8) -> usize {
+        self.add_tilei(if self.tiledata {0} else {256}, tilei)
     }
 
     pub fn add_tilei(&self, base: usize, tilei: u8) -> usize {
@@ -515,224 +1009,466 @@ impl Gpu {
         }
     }
 
-    fn render_background(&mut self, scanline: &mut [u8; WIDTH]) {
-        let mapbase = self.bgbase();
-        let line = self.ly as usize + self.scy as usize;
+    // Renders every cached tile into an RGBA image of
+    // TILE_DUMP_WIDTH x TILE_DUMP_HEIGHT pixels, so the frontend can save it
+    pub fn tile_dump(&self) -> Vec<u8> {
+        let mut img = vec![0u8; TILE_DUMP_WIDTH * TILE_DUMP_HEIGHT * 4];
 
-        // Now offset from the base to the right location. We divide by 8
-        // because each tile is 8 pixels high. We then multiply by 32
-        // because each row is 32 bytes long. We can't just multiply by 4
-        // because we need the truncation to happen beforehand
-        let mapbase = mapbase + (((line % 256) >> 3) << 5);
-
-        // X and Y location inside the tile itself to paint
-        let y = (self.ly.wrapping_add(self.scy)) % 8;
-        let mut x = self.scx % 8;
-
-        // Offset into the canvas to draw. line * width * 4 colors
-        let mut coff = (self.ly as usize) * WIDTH * 4;
+        for y in 0..TILE_DUMP_HEIGHT {
+            for x in 0..TILE_DUMP_WIDTH {
+                let tilei_x = x / 8;
+                let tilei_y = y / 8;
+                let tilei = tilei_x + 16 * tilei_y;
 
-        let mut i = 0;
-        // this.tiledata is a flag to determine which tile data table to use
-        // 0=8800-97FF, 1=8000-8FFF. For some odd reason, if tiledata = 0, then
-        // (&tiles[0]) == 0x9000, where if tiledata = 1, (&tiles[0]) = 0x8000.
-        // This implies that the indices are treated as signed numbers.
-        // TODO: should this be 0x800 ?
-        let tilebase = if !self.tiledata {256} else {0};
+                let tile = self.tiles.data[tilei];
 
-        //info!("render background. mapbase:{:x} scx:{} scy:{}", mapbase, self.scx, self.scy);
+                let colori = tile[y % 8][x % 8];
 
-        // let mut bg_tiles = [0u8; 20];
-        // let mut loop_c: usize = 0;
-        loop {
-            // Backgrounds wrap around, so calculate the offset into the bgmap
-            // each loop to check for wrapping
-            let mapoff = ((i as usize + self.scx as usize) % 256) >> 3;
-            let tilei = self.vrambank[mapbase + mapoff];
-            // bg_tiles[loop_c] = tilei;
-            // tiledata = 0 => tilei is a signed byte, so fix it here
-            let tilebase = tilei%192;//self.add_tilei(tilebase, tilei);
-            // println!("tilebase: {}", tilebase);
-
-            let row;
-            let bgpri;
-            let hflip;
-            let bgp;
-
-            row = self.tiles.data[tilebase as usize][y as usize];
-            bgpri = false;
-            hflip = false;
-            bgp = self.pal.bg;
-
-            while x < 8 && i < WIDTH as u8 {
-                let colori = row[if hflip {7 - x} else {x} as usize];
-
-                // To indicate bg priority, list a color >= 4
-                scanline[i as usize] = if bgpri {4} else {colori};
-
-                set_pixel_index(&mut self.image_data, coff, colori as usize, &bgp);
-
-                x += 1;
-                i += 1;
-                coff += 4;
+                let first_byte = (y * TILE_DUMP_WIDTH + x) * 4;
+                img[first_byte..first_byte + 4].copy_from_slice(&self.scheme.bg[colori as usize]);
             }
-
-            x = 0;
-            // loop_c += 1;
-            if i >= WIDTH as u8 { break }
         }
 
-        // Dump bg tiles
-        // println!("LINE: {:03} | LY: {:03} | {:?}", line, self.ly, bg_tiles);
+        img
     }
+}
+
+
+#[inline]
+fn set_pixel(image_data: &mut ScreenData, x: usize, y: usize, r: u8, g: u8, b: u8) {
+    let first_byte = 4 * (x + (y * 160)) as usize;
+
+    image_data[first_byte] = r;      // R
+    image_data[first_byte+1] = g;    // G
+    image_data[first_byte+2] = b;    // B
+    image_data[first_byte+3] = 255;  // A
+}
+
+#[inline]
+fn set_pixel_index(image_data: &mut ScreenData, first_byte: usize, colori: usize, pal: &Palette) {
+    image_data[first_byte] = pal[colori][0];    // R
+    image_data[first_byte+1] = pal[colori][1];  // G
+    image_data[first_byte+2] = pal[colori][2];  // B
+    image_data[first_byte+3] = pal[colori][3];  // A
+}
+
+// Writes to CGB palette RAM through BCPD/OCPD, recompiling the color
+fn write_cram(cram: &mut [u8; CRAM_SIZE], spec: &mut u8, pal: &mut [Palette; 8], val: u8) {
+    let i = (*spec & 0x3F) as usize;
+    cram[i] = val;
+    compile_cgb_color(cram, pal, i);
 
-    fn render_window(&mut self, scanline: &mut [u8; WIDTH]) {
-        // TODO: Window rendering
+    if *spec & 0x80 != 0 {
+        *spec = 0x80 | ((*spec + 1) & 0x3F);
     }
+}
 
-    fn render_sprites(&mut self, scanline: &mut [u8; WIDTH]) {
-        let line = self.ly as i32;
-        let ysize = if self.objsize {16} else {8};
+// Compiles the color that the given byte of palette RAM is part of. Colors
+// are 2 bytes, little endian, 5 bits each for red, green and blue.
+fn compile_cgb_color(cram: &[u8; CRAM_SIZE], pal: &mut [Palette; 8], i: usize) {
+    let rgb = cram[i & !1] as u16 | (cram[i | 1] as u16) << 8;
+    let scale = |val: u16| ((val & 0x1F) * 255 / 31) as u8;
+    pal[i / 8][(i % 8) / 2] = [scale(rgb), scale(rgb >> 5), scale(rgb >> 10), 255];
+}
 
-        // All sprits are located in OAM
-        // There are 40 sprites in total, each is 4 bytes wide
-        for sprite in self.oam.chunks(4) {
-            let mut yoff = (sprite[0] as i32) - 16;
-            let xoff = (sprite[1] as i32) - 8;
-            let mut tile = sprite[2] as usize;
-            let flags = sprite[3];
-
-            // First make sure that this sprite even lands on the current line
-            // being rendered. The y value in the sprite is the top left corner,
-            // so if that is below the scanline or the bottom of the sprite
-            // (which is 8 pixels high) lands below the scanline, this sprite
-            // doesn't need to be rendered right now
-            if yoff > line || yoff + ysize <= line ||
-               xoff <= -8 || xoff >= WIDTH as i32 {
-               continue
-            }
+// Update the cached palettes for BG/OBP0/OBP1. This should be called whenever
+// these registers are modified
+fn update_pal(pal: &mut Palette, val: u8, shades: &Palette) {
+    // These registers are indices into the actual palette. See
+    // http://problemkaputt.de/pandocs.htm#lcdmonochromepalettes
+    pal[0] = shades[((val >> 0) & 0x3) as usize];
+    pal[1] = shades[((val >> 2) & 0x3) as usize];
+    pal[2] = shades[((val >> 4) & 0x3) as usize];
+    pal[3] = shades[((val >> 6) & 0x3) as usize];
+    info!("BG Color: {:?} val {:02X}", pal, val);
+}
 
-            // 8x16 tiles always use adjacent tile indices. If we're in 8x16
-            // mode and this sprite needs the second tile, add 1 to the tile
-            // index and change yoff so it looks like we're rendering that tile
-            if ysize == 16 {
-                tile &= 0xfe; // ignore the lowest bit
-                if line - yoff >= 8 {
-                    tile |= 1;
-                    yoff += 8;
-                }
-            }
+//  ======================================
+//  |               TESTS                |
+//  ======================================
 
-            // 160px/line, 4 entries/px
-            let mut coff = (WIDTH as i32 * line + xoff) * 4;
+#[cfg(test)]
+mod gpu_tests {
+    use super::*;
 
-            // All sprite tile palettes are at 0x8000-0x8fff => start of vram.
-            // If we're in CGB mode, then we get our palette from the spite
-            // flags. We also need to take into account the tile being in a
-            // different bank. Otherwise, we just use the tile index as a raw
-            // index.
-            // bit4 is the palette number. 0 = obp0, 1 = obp1
-           let pal = if flags & 0x10 != 0 {self.pal.obp1} else {self.pal.obp0};
-           let tiled = self.tiles.data[tile as usize];
+    // Runs the GPU until LY reaches the given line
+    fn run_until_line(gpu: &mut Gpu, line: u8) {
+        let mut if_ = 0;
+        while gpu.ly != line {
+            gpu.step(4, &mut if_);
+        }
+    }
 
+    // Turns the LCD on and runs through the blank frame that follows
+    fn lcd_on(gpu: &mut Gpu, lcdc: u8) {
+        gpu.wb(0xFF40, lcdc);
+        run_until_line(gpu, 144);
+        run_until_line(gpu, 0);
+    }
 
-            // bit6 is the vertical flip bit
-            let row = if flags & 0x40 != 0 {
-                tiled[(7 - (line - yoff)) as usize]
-            } else {
-                tiled[(line - yoff) as usize]
-            };
-
-            for x in 0..8 {
-                coff += 4;
-
-                // If these pixels are off screen, don't bother drawing
-                // anything. Also, if the background tile at this pixel has
-                // priority, don't render this sprite at all.
-                if xoff + x < 0 || xoff + x >= WIDTH as i32 ||
-                   scanline[(x + xoff) as usize] > 3 {
-                    continue
-                }
-                // bit5 is the horizontal flip flag
-                let colori = row[if flags & 0x20 != 0 {7-x} else {x} as usize];
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> Color {
+        let i = (y * WIDTH + x) * 4;
+        [gpu.image_data[i], gpu.image_data[i + 1], gpu.image_data[i + 2], gpu.image_data[i + 3]]
+    }
 
-                // A color index of 0 for sprites means transparent
-                if colori == 0 { continue }
+    #[test]
+    fn window() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF47, 0xE4);
 
-                // bit7 0=OBJ Above BG, 1=OBJ Behind BG color 1-3. So if this
-                // sprite has this flag set and the data at this location
-                // already contains data (nonzero), then don't render this
-                // sprite
-                if flags & 0x80 != 0 && scanline[(xoff + x) as usize] != 0 {
-                    continue
-                }
+        // Tile 1 is all color 3. The BG map is all tile 0, the window map
+        // (9C00) all tile 1
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in 0x9C00..0xA000 {
+            gpu.wb_vram(addr, 0x01);
+        }
+        gpu.wb(0xFF4A, 10);     // WY
+        gpu.wb(0xFF4B, 7 + 80); // WX
+        lcd_on(&mut gpu, 0xF1);   // LCD, window map 9C00, window, 8000 tile data, BG
+
+        run_until_line(&mut gpu, 20);
+        let bg = gpu.pal.bg;
+        assert_eq!(pixel(&gpu, 100, 9), bg[0]);
+        assert_eq!(pixel(&gpu, 79, 10), bg[0]);
+        assert_eq!(pixel(&gpu, 80, 10), bg[3]);
+        assert_eq!(pixel(&gpu, 159, 19), bg[3]);
+    }
 
-                let color = pal[colori as usize];
+    #[test]
+    fn window_line_counter() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF47, 0xE4);
 
-                let palette = if flags & 0x10 != 0 {self.pal.obp0} else {self.pal.obp1};
-                set_pixel_index(&mut self.image_data, coff as usize - 4, colori as usize, &palette);
-            }
+        // Window map: first tile row is tile 0 (color 0), second is tile 1 (color 3)
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
         }
+        for addr in 0x9C20..0x9C40 {
+            gpu.wb_vram(addr, 0x01);
+        }
+        gpu.wb(0xFF4A, 0);
+        gpu.wb(0xFF4B, 7);
+        lcd_on(&mut gpu, 0xF1);
+
+        // Hide the window for lines 4-11. The window's 8th line still shows
+        // up at line 16 instead of line 8.
+        run_until_line(&mut gpu, 4);
+        gpu.wb(0xFF40, 0xD1);
+        run_until_line(&mut gpu, 12);
+        gpu.wb(0xFF40, 0xF1);
+        run_until_line(&mut gpu, 20);
+
+        let bg = gpu.pal.bg;
+        assert_eq!(pixel(&gpu, 0, 15), bg[0]);
+        assert_eq!(pixel(&gpu, 0, 16), bg[3]);
     }
 
-    pub fn dump_tiles(&self) {
-        use image::{ImageBuffer, RgbaImage, Rgba};
+    #[test]
+    fn signed_tile_data() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF47, 0xE4);
 
-        static TILE_SIZE_X: u32 = 16 * 8;
-        static TILE_SIZE_Y: u32 = 12 * 8;
+        // Color 3 at 9000 (tile 0 in signed mode), color 1 at 8800 (tile -128)
+        for addr in 0x9000..0x9010 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in (0x8800..0x8810).filter(|addr| addr % 2 == 0) {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        gpu.wb_vram(0x9801, 0x80);
+        lcd_on(&mut gpu, 0x81);   // LCD, 8800 tile data, BG map 9800, BG
+
+        run_until_line(&mut gpu, 1);
+        let bg = gpu.pal.bg;
+        assert_eq!(pixel(&gpu, 0, 0), bg[3]);
+        assert_eq!(pixel(&gpu, 8, 0), bg[1]);
+
+        // Unsigned mode, tile 0 is at 8000
+        gpu.wb(0xFF40, 0x91);
+        run_until_line(&mut gpu, 2);
+        assert_eq!(pixel(&gpu, 0, 1), bg[0]);
+    }
 
-        let mut img: RgbaImage = ImageBuffer::new(TILE_SIZE_X, TILE_SIZE_Y);
+    // LCD on with sprites, BG map all tile 0. Tile 1 is all color 3, tile 2
+    // all color 1
+    fn sprite_gpu() -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0xFF49, 0x1B);
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in (0x8020..0x8030).filter(|addr| addr % 2 == 0) {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        lcd_on(&mut gpu, 0x93);
+        gpu
+    }
 
-        for y in 0..TILE_SIZE_Y as usize {
-            for x in 0..TILE_SIZE_X as usize {
-                let tilei_x = x / 8;
-                let tilei_y = y / 8;
-                let tilei = tilei_x + 16 * tilei_y;
+    fn set_sprite(gpu: &mut Gpu, i: usize, y: u8, x: u8, tile: u8, flags: u8) {
+        gpu.oam[i * 4..i * 4 + 4].copy_from_slice(&[y + 16, x + 8, tile, flags]);
+    }
 
-                let tile = self.tiles.data[tilei];
+    #[test]
+    fn sprites_per_line() {
+        let mut gpu = sprite_gpu();
+        // 11 sprites on line 0, spread over X. The last one doesn't get drawn.
+        for i in 0..11 {
+            set_sprite(&mut gpu, i, 0, i as u8 * 8, 1, 0);
+        }
 
-                let colori = tile[y % 8][x % 8];
+        run_until_line(&mut gpu, 1);
+        let obp0 = gpu.pal.obp0;
+        assert_eq!(pixel(&gpu, 9 * 8, 0), obp0[3]);
+        assert_eq!(pixel(&gpu, 10 * 8, 0), gpu.pal.bg[0]);
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let mut gpu = sprite_gpu();
+        // Lower X wins, even though it's later in OAM
+        set_sprite(&mut gpu, 0, 0, 4, 2, 0x10);
+        set_sprite(&mut gpu, 1, 0, 0, 1, 0x00);
+        // Same X, lower OAM index wins
+        set_sprite(&mut gpu, 2, 0, 40, 1, 0x10);
+        set_sprite(&mut gpu, 3, 0, 40, 2, 0x00);
+
+        run_until_line(&mut gpu, 1);
+        let (obp0, obp1) = (gpu.pal.obp0, gpu.pal.obp1);
+        assert_eq!(pixel(&gpu, 7, 0), obp0[3]);
+        assert_eq!(pixel(&gpu, 8, 0), obp1[1]);
+        assert_eq!(pixel(&gpu, 40, 0), obp1[3]);
+    }
+
+    // Length of line 1's mode 3, in dots
+    fn mode3_length(gpu: &mut Gpu) -> u32 {
+        let mut if_ = 0;
+        run_until_line(gpu, 1);
+        while gpu.mode != Mode::RdVram {
+            gpu.step(1, &mut if_);
+        }
+        let mut dots = 0;
+        while gpu.mode == Mode::RdVram {
+            gpu.step(1, &mut if_);
+            dots += 1;
+        }
+        dots
+    }
 
-                let r = PALETTE[colori as usize][0];
-                let g = PALETTE[colori as usize][1];
-                let b = PALETTE[colori as usize][2];
+    #[test]
+    fn mode3_timing() {
+        // Not counting the first dot
+        let base = mode3_length(&mut sprite_gpu());
+        assert_eq!(base, 171);
+
+        // SCX % 8 pixels are thrown away at the start of the line
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF43, 3);
+        assert_eq!(mode3_length(&mut gpu), base + 3);
+
+        // Fetching a sprite stops the FIFO for 6 to 11 dots
+        let mut gpu = sprite_gpu();
+        set_sprite(&mut gpu, 0, 1, 20, 1, 0);
+        let length = mode3_length(&mut gpu);
+        assert!(base + 6 <= length && length <= base + 11);
+
+        // The fetcher starts over at the window
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF4B, 7 + 80);
+        gpu.wb(0xFF40, 0xB3);
+        assert_eq!(mode3_length(&mut gpu), base + 6);
+    }
+
+    #[test]
+    fn mid_line_palette() {
+        let mut gpu = sprite_gpu();
+        let mut if_ = 0;
+        run_until_line(&mut gpu, 1);
 
-                img.put_pixel(x as u32, y as u32, Rgba { data: [r, g, b, 255]})
+        // BGP changes after the first 80 pixels of the line
+        while gpu.mode != Mode::RdVram || gpu.lx < 80 {
+            gpu.step(1, &mut if_);
+        }
+        gpu.wb(0xFF47, 0xE7);
+
+        run_until_line(&mut gpu, 2);
+        assert_eq!(pixel(&gpu, 79, 1), gpu.scheme.bg[0]);
+        assert_eq!(pixel(&gpu, 80, 1), gpu.scheme.bg[3]);
+    }
+
+    // Steps a dot at a time and counts STAT interrupts until LY reaches the
+    // given line
+    fn count_stat_irqs(gpu: &mut Gpu, line: u8) -> u32 {
+        let mut count = 0;
+        loop {
+            let mut if_ = 0;
+            gpu.step(1, &mut if_);
+            if if_ & Interrupt::LCDStat as u8 != 0 {
+                count += 1;
+            }
+            if gpu.ly == line && gpu.clock == 0 {
+                return count;
             }
         }
+    }
 
-        img.save("tile_dump.png").unwrap();
-        info!("Tiles dumped to tile_dump.png");
+    #[test]
+    fn stat_register() {
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF45, 5);
+        gpu.wb(0xFF41, 0x40);
+        assert_eq!(gpu.rb(0xFF41) & 0xFC, 0xC0);
+
+        run_until_line(&mut gpu, 5);
+        assert_eq!(gpu.rb(0xFF41) & 0xFC, 0xC4);
+
+        // Writing LYC compares right away
+        gpu.wb(0xFF45, 6);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0);
     }
-}
 
+    #[test]
+    fn stat_irq_blocking() {
+        let mut gpu = sprite_gpu();
+        run_until_line(&mut gpu, 0);
+
+        // One interrupt for each hblank
+        gpu.wb(0xFF41, 0x08);
+        assert_eq!(count_stat_irqs(&mut gpu, 0), 144);
+
+        // Line 10 matches LYC from the end of line 9's hblank to the start of
+        // line 11, so the line never goes low and line 10's hblank doesn't
+        // interrupt
+        gpu.wb(0xFF41, 0x48);
+        gpu.wb(0xFF45, 10);
+        assert_eq!(count_stat_irqs(&mut gpu, 0), 143);
+
+        // Mode 1 and mode 2 interrupts. Enabling them in the middle of line
+        // 0's OAM scan interrupts right away. The line goes from vblank
+        // straight to the next line 0's OAM scan, so that one is blocked.
+        gpu.wb(0xFF41, 0x30);
+        assert_eq!(count_stat_irqs(&mut gpu, 0), 1 + 143 + 1);
+    }
 
-#[inline]
-fn set_pixel(image_data: &mut ScreenData, x: usize, y: usize, r: u8, g: u8, b: u8) {
-    let first_byte = 4 * (x + (y * 160)) as usize;
+    #[test]
+    fn line_153() {
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF45, 0);
+        run_until_line(&mut gpu, 153);
+        assert_eq!(gpu.rb(0xFF44), 153);
+
+        // LY goes back to 0 early, still in vblank, and matches LYC = 0
+        let mut if_ = 0;
+        gpu.step(8, &mut if_);
+        assert_eq!(gpu.rb(0xFF44), 0);
+        assert_eq!(gpu.rb(0xFF41) & 0x07, 0x05);
+    }
 
-    image_data[first_byte] = r;      // R
-    image_data[first_byte+1] = g;    // G
-    image_data[first_byte+2] = b;    // B
-    image_data[first_byte+3] = 255;  // A
-}
+    #[test]
+    fn lcd_off_and_on() {
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF47, 0xFF);
+        gpu.wb(0xFF41, 0x78);
+        run_until_line(&mut gpu, 100);
+        gpu.drain_events();
+
+        // Blank screen, LY 0 and mode 0, and no interrupts
+        gpu.wb(0xFF40, 0x13);
+        assert_eq!(gpu.drain_events(), vec![GpuEvent::LcdOff]);
+        assert_eq!(pixel(&gpu, 80, 50), gpu.scheme.bg[0]);
+        let mut if_ = 0;
+        gpu.step(70224, &mut if_);
+        assert_eq!(if_, 0);
+        assert_eq!(gpu.rb(0xFF44), 0);
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 0);
+
+        // The first frame after turning it back on isn't drawn
+        gpu.wb(0xFF40, 0x93);
+        assert_eq!(gpu.drain_events(), vec![GpuEvent::LcdOn]);
+        run_until_line(&mut gpu, 144);
+        assert_eq!(pixel(&gpu, 80, 50), gpu.scheme.bg[0]);
+        run_until_line(&mut gpu, 0);
+        run_until_line(&mut gpu, 144);
+        assert_eq!(pixel(&gpu, 80, 50), gpu.scheme.bg[3]);
+    }
 
-#[inline]
-fn set_pixel_index(image_data: &mut ScreenData, first_byte: usize, colori: usize, pal: &Palette) {
-    image_data[first_byte] = pal[colori][0];    // R
-    image_data[first_byte+1] = pal[colori][1];  // G
-    image_data[first_byte+2] = pal[colori][2];  // B
-    image_data[first_byte+3] = pal[colori][0];  // A
-}
+    #[test]
+    fn color_schemes() {
+        let mut gpu = sprite_gpu();
+        gpu.wb(0xFF47, 0x1B);
+        set_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        set_sprite(&mut gpu, 1, 0, 8, 1, 0x10);
+
+        // Each layer gets its own colors, and the registers still pick from them
+        let scheme = ::palette::SCHEMES[3].1;
+        gpu.set_color_scheme(scheme);
+        run_until_line(&mut gpu, 1);
+        assert_eq!(pixel(&gpu, 0, 0), scheme.obj0[3]);
+        assert_eq!(pixel(&gpu, 8, 0), scheme.obj1[0]);
+        assert_eq!(pixel(&gpu, 16, 0), scheme.bg[3]);
+    }
 
-// Update the cached palettes for BG/OBP0/OBP1. This should be called whenever
-// these registers are modified
-fn update_pal(pal: &mut Palette, val: u8) {
-    // These registers are indices into the actual palette. See
-    // http://problemkaputt.de/pandocs.htm#lcdmonochromepalettes
-    pal[0] = PALETTE[((val >> 0) & 0x3) as usize];
-    pal[1] = PALETTE[((val >> 2) & 0x3) as usize];
-    pal[2] = PALETTE[((val >> 4) & 0x3) as usize];
-    pal[3] = PALETTE[((val >> 6) & 0x3) as usize];
-    info!("BG Color: {:?} val {:02X}", pal, val);
-}
\ No newline at end of file
+    // Writes RGB555 colors to CGB palette RAM from the given byte on, through
+    // BCPS/BCPD or OCPS/OCPD
+    fn write_cgb_colors(gpu: &mut Gpu, spec: u16, index: u8, colors: &[u16]) {
+        gpu.wb(spec, 0x80 | index);
+        for &color in colors {
+            gpu.wb(spec + 1, color as u8);
+            gpu.wb(spec + 1, (color >> 8) as u8);
+        }
+    }
+
+    const WHITE: Color = [255, 255, 255, 255];
+    const RED: Color = [255, 0, 0, 255];
+    const BLUE: Color = [0, 0, 255, 255];
+
+    #[test]
+    fn cgb_bg_attributes() {
+        let mut gpu = Gpu::new();
+        gpu.is_cgb = true;
+        // BG palette 1 is white, red, green, blue
+        write_cgb_colors(&mut gpu, 0xFF68, 8, &[0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+        gpu.wb(0xFF68, 10);
+        assert_eq!(gpu.rb(0xFF69), 0x1F);
+
+        // Tile 1 in bank 1 has color 1 on its left half, bank 0's is empty
+        gpu.wb(0xFF4F, 1);
+        for addr in (0x8010..0x8020).filter(|addr| addr % 2 == 0) {
+            gpu.wb_vram(addr, 0xF0);
+        }
+        // The first map entry is tile 1 from bank 1, flipped horizontally,
+        // with palette 1
+        gpu.wb_vram(0x9800, 0x29);
+        gpu.wb(0xFF4F, 0);
+        gpu.wb_vram(0x9800, 0x01);
+        assert_eq!(gpu.rb(0xFF4F), 0xFE);
+
+        lcd_on(&mut gpu, 0x91);
+        run_until_line(&mut gpu, 1);
+        assert_eq!(pixel(&gpu, 0, 0), WHITE);
+        assert_eq!(pixel(&gpu, 7, 0), RED);
+    }
+
+    #[test]
+    fn cgb_sprite_priority() {
+        let mut gpu = Gpu::new();
+        gpu.is_cgb = true;
+        write_cgb_colors(&mut gpu, 0xFF6A, 6, &[0x001F]);
+        write_cgb_colors(&mut gpu, 0xFF6A, 14, &[0x7C00]);
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+
+        // The lower OAM index wins, whatever the X
+        set_sprite(&mut gpu, 0, 0, 4, 1, 0x01);
+        set_sprite(&mut gpu, 1, 0, 0, 1, 0x00);
+        lcd_on(&mut gpu, 0x93);
+        run_until_line(&mut gpu, 1);
+        assert_eq!(pixel(&gpu, 0, 0), RED);
+        assert_eq!(pixel(&gpu, 4, 0), BLUE);
+        assert_eq!(pixel(&gpu, 12, 0), WHITE);
+    }
+}
@@ -1,4 +1,15 @@
-use piston::input::Key;
+// Game Boy joypad buttons. The frontend maps its own keys to these.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+}
 
 pub struct Input {
     rows: [u8; 2],
@@ -27,32 +38,30 @@ impl Input {
         self.column = data & 0x30;
     }
 
-    pub fn key_press(&mut self, key: &Key) {
-        debug!("{:?} pressed", key);
-        match *key {
-            Key::Return => {self.rows[0] &= 0x7}
-            Key::Space =>  {self.rows[0] &= 0xB}
-            Key::Left =>   {self.rows[1] &= 0xD}
-            Key::Up =>     {self.rows[1] &= 0xB}
-            Key::Right =>  {self.rows[1] &= 0xE}
-            Key::Down =>   {self.rows[1] &= 0x7}
-            Key::X =>      {self.rows[0] &= 0xD}
-            Key::Z =>      {self.rows[0] &= 0xE}
-            _ => {}
+    pub fn key_press(&mut self, button: Button) {
+        debug!("{:?} pressed", button);
+        match button {
+            Button::Start =>  {self.rows[0] &= 0x7}
+            Button::Select => {self.rows[0] &= 0xB}
+            Button::Left =>   {self.rows[1] &= 0xD}
+            Button::Up =>     {self.rows[1] &= 0xB}
+            Button::Right =>  {self.rows[1] &= 0xE}
+            Button::Down =>   {self.rows[1] &= 0x7}
+            Button::B =>      {self.rows[0] &= 0xD}
+            Button::A =>      {self.rows[0] &= 0xE}
         }
     }
-    pub fn key_release(&mut self, key: &Key) {
-        debug!("{:?} released", key);
-        match *key {
-            Key::Return => {self.rows[0] |= 0x8}
-            Key::Space =>  {self.rows[0] |= 0x4}
-            Key::Left =>   {self.rows[1] |= 0x2}
-            Key::Up =>     {self.rows[1] |= 0x4}
-            Key::Right =>  {self.rows[1] |= 0x1}
-            Key::Down =>   {self.rows[1] |= 0x8}
-            Key::X =>      {self.rows[0] |= 0x2}
-            Key::Z =>      {self.rows[0] |= 0x1}
-            _ => {}     
+    pub fn key_release(&mut self, button: Button) {
+        debug!("{:?} released", button);
+        match button {
+            Button::Start =>  {self.rows[0] |= 0x8}
+            Button::Select => {self.rows[0] |= 0x4}
+            Button::Left =>   {self.rows[1] |= 0x2}
+            Button::Up =>     {self.rows[1] |= 0x4}
+            Button::Right =>  {self.rows[1] |= 0x1}
+            Button::Down =>   {self.rows[1] |= 0x8}
+            Button::B =>      {self.rows[0] |= 0x2}
+            Button::A =>      {self.rows[0] |= 0x1}
         }
     }
-}
\ No newline at end of file
+}
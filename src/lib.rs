@@ -0,0 +1,33 @@
+//
+//      RustBoy emulator core
+//
+// Everything needed to run a Game Boy ROM without any windowing or graphics
+// dependencies. Frontends drive an `Emulator` and receive frames through a
+// `FrameOutput`.
+//
+
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+#[macro_use]
+extern crate log;
+extern crate colored;
+
+pub mod cpu;
+pub mod gpu;
+pub mod palette;
+pub mod apu;
+pub mod mmu;
+pub mod mapper;
+pub mod cartridge;
+pub mod emulator;
+pub mod timer;
+pub mod rtc;
+pub mod input;
+pub mod state;
+pub mod wav;
+
+pub use emulator::{Emulator, FrameOutput, NullOutput};
+pub use input::Button;
+pub use gpu::GpuEvent;
+pub use palette::ColorScheme;
@@ -18,13 +18,14 @@ extern crate gfx_graphics;
 extern crate gfx_device_gl;
 extern crate gfx_text;
 extern crate fps_counter;
+extern crate rustboy;
 
 use std::env;
 use env_logger::LogBuilder;
 use log::LogRecord;
 use colored::*;
 
-use piston_window::{OpenGL, PistonWindow, WindowSettings, Texture};
+use piston_window::{OpenGL, PistonWindow, WindowSettings, Texture, Image};
 use glfw_window::GlfwWindow;
 use piston::window::AdvancedWindow;
 use piston::event_loop::EventLoop;
@@ -32,20 +33,20 @@ use piston::input::*;
 use fps_counter::FPSCounter;
 
 use graphics::clear;
+use graphics::types::SourceRectangle;
 use texture::*;
 
-mod cpu;
-mod gpu;
-mod mmu;
-mod cartridge;
-mod emulator;
-mod timer;
-mod input;
+use rustboy::{cpu, emulator, gpu};
+use rustboy::emulator::FrameOutput;
+use rustboy::gpu::{ScreenData, GpuEvent};
+use rustboy::input::Button as GbButton;
 
 const OPENGL: OpenGL = OpenGL::V3_2;
 static DEFAULT_LOG_LEVEL: &'static str = "debug";
 static DEFAULT_LOG_LEVELS: &'static str = "gfx_device_gl=warn,cargo=error";
 static WINDOW_TITLE: &'static str = "Rust Boy Emulator";
+static USAGE: &'static str =
+    "USAGE: rustboy-emu <path/to/rom> [--wav <path/to/output.wav>] [--palette <path/to/palette.txt>]";
 
 const SCREEN_MULT: u32 = 4;
 const BG_COLOR: [f32; 4] = [5./255., 36./255., 5./255., 1.0];
@@ -57,6 +58,61 @@ const SCREEN_DIMS: [u32; 2] = [NATIVE_DIMS[0] * SCREEN_MULT,
                                NATIVE_DIMS[1] * SCREEN_MULT];
 const FONT_SIZE: u8 = (1. + SCREEN_MULT as f32 * 4.5) as u8;
 
+// Holds the last frame the emulator produced until it's uploaded to the
+// framebuffer texture on the next render event
+struct WindowOutput {
+    image_data: Box<ScreenData>,
+    is_lcd_on: bool,
+}
+
+impl FrameOutput for WindowOutput {
+    fn frame(&mut self, data: &ScreenData) {
+        self.image_data.copy_from_slice(data);
+    }
+
+    fn gpu_event(&mut self, event: GpuEvent) {
+        debug!("GPU event: {:?}", event);
+        self.is_lcd_on = event == GpuEvent::LcdOn;
+    }
+}
+
+// Maps the number row to save state slots
+fn map_slot(key: Key) -> Option<u8> {
+    match key {
+        Key::D0 => Some(0), Key::D1 => Some(1), Key::D2 => Some(2),
+        Key::D3 => Some(3), Key::D4 => Some(4), Key::D5 => Some(5),
+        Key::D6 => Some(6), Key::D7 => Some(7), Key::D8 => Some(8),
+        Key::D9 => Some(9),
+        _ => None,
+    }
+}
+
+// Maps keyboard keys to Game Boy buttons
+fn map_key(key: Key) -> Option<GbButton> {
+    match key {
+        Key::Return => Some(GbButton::Start),
+        Key::Space =>  Some(GbButton::Select),
+        Key::Left =>   Some(GbButton::Left),
+        Key::Up =>     Some(GbButton::Up),
+        Key::Right =>  Some(GbButton::Right),
+        Key::Down =>   Some(GbButton::Down),
+        Key::X =>      Some(GbButton::B),
+        Key::Z =>      Some(GbButton::A),
+        _ => None,
+    }
+}
+
+// Saves the GPU's tile cache to a png
+fn dump_tiles(gpu: &gpu::Gpu) {
+    use image::{ImageBuffer, RgbaImage};
+
+    let img: RgbaImage = ImageBuffer::from_raw(
+        gpu::TILE_DUMP_WIDTH as u32, gpu::TILE_DUMP_HEIGHT as u32, gpu.tile_dump()).unwrap();
+
+    img.save("tile_dump.png").unwrap();
+    info!("Tiles dumped to tile_dump.png");
+}
+
 
 fn main() {
 
@@ -83,14 +139,23 @@ fn main() {
 
     // Argument parsing
     let args: Vec<_> = env::args().collect();
-    let rom_path: &String;
-
-    match args.len() {
-        2 => rom_path = &args[1],
-        _ => {
-            error!("No arguments provided.\nUSAGE: rustboy-emu <path/to/rom>");
-            return;
-        },
+    if args.len() < 2 || args.len() % 2 != 0 {
+        error!("Invalid arguments.\n{}", USAGE);
+        return;
+    }
+    let rom_path = &args[1];
+    let mut wav_path: Option<&String> = None;
+    let mut palette_path: Option<&String> = None;
+
+    for option in args[2..].chunks(2) {
+        match option[0].as_str() {
+            "--wav" => wav_path = Some(&option[1]),
+            "--palette" => palette_path = Some(&option[1]),
+            _ => {
+                error!("Invalid argument: {}\n{}", option[0], USAGE);
+                return;
+            },
+        }
     }
 
     // Window creation
@@ -106,7 +171,35 @@ fn main() {
     window.set_ups(60);
 
     // Initialize emulator
-    let mut emu = emulator::Emulator::new(&window, rom_path);
+    let mut emu = match emulator::Emulator::new(emulator::try_open_rom(rom_path)) {
+        Ok(emu) => emu,
+        Err(why) => {
+            error!("Couldn't start the emulator: {}", why);
+            return;
+        }
+    };
+    if cpu::WADATSUMI_DEBUG {
+        if let Err(why) = emu.cpu.enable_trace("trace_log.txt") {
+            error!("Couldn't open the trace log: {}", why);
+        }
+    }
+    if let Err(why) = emu.load_save_file(emulator::save_path(rom_path)) {
+        error!("Couldn't load save file: {}", why);
+    }
+    if let Some(path) = palette_path {
+        if let Err(why) = emu.load_palette_file(path) {
+            error!("Couldn't load palette: {}", why);
+        }
+    }
+    if let Some(path) = wav_path {
+        if let Err(why) = emu.start_wav_recording(path) {
+            error!("Couldn't start WAV recording: {}", why);
+        }
+    }
+    let mut output = WindowOutput {
+        image_data: Box::new([0; gpu::WIDTH * gpu::HEIGHT * 4]),
+        is_lcd_on: emu.mem.gpu.lcdon,
+    };
 
     // Append game name to title
     window.set_title(
@@ -128,12 +221,19 @@ fn main() {
     // Set up framebuffer
     let ts = TextureSettings::new().filter(texture::Filter::Nearest).compress(false).generate_mipmap(false);
     let mut framebuffer =
-        Texture::create(&mut window.factory, Format::Rgba8, &*emu.mem.gpu.image_data, NATIVE_DIMS, &ts)
+        Texture::create(&mut window.factory, Format::Rgba8, &*output.image_data, NATIVE_DIMS, &ts)
         .expect("Couldn't create framebuffer texture");
+    let img = {
+        let r: SourceRectangle = [0.0, 0.0, SCREEN_DIMS[0] as f64, SCREEN_DIMS[1] as f64];
+        Image::new().src_rect(r)
+    };
 
     // Set up framerate counter
     let mut fps = FPSCounter::new();
 
+    // Save state slot used by F5/F8
+    let mut state_slot: u8 = 0;
+
     // Main Event Loop
     while let Some(evt) = window.next() {
         //debug!("EVENT: {:?}", evt);
@@ -150,16 +250,43 @@ fn main() {
 
         // T to dump all tiles to a png
         if let Some(Button::Keyboard(Key::T)) = evt.press_args() {
-            emu.mem.gpu.dump_tiles();
+            dump_tiles(&emu.mem.gpu);
+        }
+
+        // P to switch between the built in color schemes
+        if let Some(Button::Keyboard(Key::P)) = evt.press_args() {
+            info!("Color scheme: {}", emu.next_color_scheme());
+        }
+
+        // 0-9 to pick a save state slot, F5 to save to it, F8 to load from it
+        if let Some(Button::Keyboard(key)) = evt.press_args() {
+            if let Some(slot) = map_slot(key) {
+                state_slot = slot;
+                info!("Save state slot {}", state_slot);
+            }
+        }
+        if let Some(Button::Keyboard(Key::F5)) = evt.press_args() {
+            if let Err(why) = emu.save_state_file(emulator::state_path(rom_path, state_slot)) {
+                error!("Couldn't save state: {}", why);
+            }
+        }
+        if let Some(Button::Keyboard(Key::F8)) = evt.press_args() {
+            if let Err(why) = emu.load_state_file(emulator::state_path(rom_path, state_slot)) {
+                error!("Couldn't load state: {}", why);
+            }
         }
 
         // If any other button was pressed, let emulator handle it
         if let Some(Button::Keyboard(key)) = evt.press_args() {
-            emu.mem.input.key_press(&key);
+            if let Some(button) = map_key(key) {
+                emu.mem.input.key_press(button);
+            }
         }
         // If any other button was released, let emulator handle it
         if let Some(Button::Keyboard(key)) = evt.release_args() {
-            emu.mem.input.key_release(&key);
+            if let Some(button) = map_key(key) {
+                emu.mem.input.key_release(button);
+            }
         }
 
         if let Event::Render(_) = evt {
@@ -169,28 +296,31 @@ fn main() {
                 clear(BG_COLOR, g);
             });
 
-            // Emulator rendering (does nothing for now, look below)
-            //emu.render(&r, &mut window, &mut framebuffer, &evt);
-
-            // TODO: Move these to the above call
-            // Update the framebuffer
+            // Update the framebuffer with the last frame the emulator output
             UpdateTexture::update(&mut framebuffer, &mut window.encoder, Format::Rgba8,
-                &*emu.mem.gpu.image_data, [0,0], NATIVE_DIMS).unwrap();
+                &*output.image_data, [0,0], NATIVE_DIMS).unwrap();
             // Draw the screen
             window.draw_2d(&evt, |c, g| {
                 use graphics::Transformed;
 
-                emu.mem.gpu.img.draw(&framebuffer, &c.draw_state,
+                img.draw(&framebuffer, &c.draw_state,
                     c.transform.scale(SCREEN_MULT as f64, SCREEN_MULT as f64), g);
             });
 
             // TODO: Move to seperate module (debugger.rs)
             // Debugger rendering
             if emu.is_debugging() {
-                let mut dbg_string = format!("\tEmulator\n{:?}\n FPS: {}\n\n", emu, fps.tick());
+                let mut dbg_string = format!("\tEmulator\n{:?}\n FPS: {}\n State slot: {}\n\n",
+                                             emu, fps.tick(), state_slot);
                 dbg_string.push_str(&format!("\tRegisters\n{:?}\n\n", emu.cpu.get_regs()));
                 dbg_string.push_str(&format!("\tFlags\n{:?}\n\n", emu.cpu.get_flags()));
                 dbg_string.push_str(&format!("\tTimers\n{:?}\n\n", emu.mem.get_timers()));
+                if emu.mem.is_rumbling() {
+                    dbg_string.push_str("\tRumble\n\n");
+                }
+                if !output.is_lcd_on {
+                    dbg_string.push_str("\tLCD off\n\n");
+                }
 
                 // Split lines and place them appropriately
                 let dbg_lines = dbg_string.split('\n');
@@ -221,8 +351,16 @@ fn main() {
             //println!("UPDATE: {}", emu.frame_count);
             if emu.is_running() {
                 debug!("FRAME START: {}", emu.frame_count);
-                emu.update(&u);
+                emu.update(&mut output);
             }
         }
     }
+
+    // Window closed
+    if let Err(why) = emu.flush_save_file() {
+        error!("Couldn't write save file: {}", why);
+    }
+    if let Err(why) = emu.stop_wav_recording() {
+        error!("Couldn't finish WAV recording: {}", why);
+    }
 }
@@ -0,0 +1,268 @@
+//
+//      MBC1: up to 2MB ROM and 32KB RAM
+//
+
+use super::*;
+
+// Nintendo logo in the cartridge header, used to find the games in a MBC1M
+// multicart
+const LOGO_OFFSET: usize = 0x104;
+const NINTENDO_LOGO: [u8; 0x30] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    enable_ram: bool,
+    is_ram_mode: bool,   // true -> RAM banking mode, else ROM banking mode
+    is_multicart: bool,  // MBC1M, the upper bank bits are shifted by one
+    rom_bank: u8,        // Lower 5 bits of the ROM bank number
+    ram_bank: u8,        // RAM bank, or upper 2 bits of the ROM bank number
+    rom_bank_mask: usize,
+
+    // Offsets into rom/ram, updated on every bank switch
+    rom0_offset: usize,  // 0000-3FFF
+    rom_offset: usize,   // 4000-7FFF
+    ram_offset: usize,   // A000-BFFF
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        let mut mbc = Mbc1 {
+            is_multicart: is_multicart(&rom),
+            rom_bank_mask: rom_bank_mask(&rom),
+            rom: rom,
+            ram: vec![0; ram_size],
+
+            enable_ram: false,
+            is_ram_mode: false,
+            rom_bank: 1,
+            ram_bank: 0,
+
+            rom0_offset: 0,
+            rom_offset: ROM_BANK_SIZE,
+            ram_offset: 0,
+        };
+        mbc.update_banks();
+        info!("MBC1 multicart: {}", mbc.is_multicart);
+
+        mbc
+    }
+
+    // Recalculates the ROM/RAM offsets from the registers
+    fn update_banks(&mut self) {
+        // MBC1M only has 4 lines for the lower bank number
+        let (lower, upper_shift) = if self.is_multicart {
+            (self.rom_bank & 0x0F, 4)
+        } else {
+            (self.rom_bank, 5)
+        };
+        let upper = (self.ram_bank as usize) << upper_shift;
+
+        let rom0_bank = if self.is_ram_mode { upper } else { 0 };
+        let rom_bank = upper | lower as usize;
+        let ram_bank = if self.is_ram_mode { self.ram_bank as usize } else { 0 };
+
+        self.rom0_offset = (rom0_bank & self.rom_bank_mask) * ROM_BANK_SIZE;
+        self.rom_offset = (rom_bank & self.rom_bank_mask) * ROM_BANK_SIZE;
+        self.ram_offset = ram_bank * RAM_BANK_SIZE;
+        debug!("MBC1 banks. 0000: {:02X}  4000: {:02X}  A000: {:X}",
+            rom0_bank & self.rom_bank_mask, rom_bank & self.rom_bank_mask, ram_bank);
+    }
+}
+
+// MBC1M carts are 1MB and wired so that the upper bank bits select one
+// of 4 games of 256KB each. There's no header flag for it, but every
+// game has its own header, so look for a logo past the first one.
+fn is_multicart(rom: &[u8]) -> bool {
+    const GAME_SIZE: usize = ROM_BANK_SIZE * 0x10;
+
+    if rom.len() != GAME_SIZE * 4 { return false }
+
+    (1..4).any(|game| {
+        let offset = game * GAME_SIZE + LOGO_OFFSET;
+        rom[offset..offset + NINTENDO_LOGO.len()] == NINTENDO_LOGO[..]
+    })
+}
+
+impl Mapper for Mbc1 {
+    fn rb_rom(&self, addr: u16) -> u8 {
+        match addr {
+            // Bank 0, or a remapped bank in RAM banking mode
+            0x0000 ... 0x3FFF => read_rom(&self.rom, self.rom0_offset + addr as usize),
+            _ => read_rom(&self.rom, self.rom_offset + (addr & 0x3FFF) as usize),
+        }
+    }
+
+    fn wb_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            // Enable external RAM if 0x0A was writtten. Disable it otherwise
+            0x0000 ... 0x1FFF => self.enable_ram = data & 0x0F == 0x0A,
+            // Switch ROM bank
+            0x2000 ... 0x3FFF => {
+                // Bank 0 can't be selected here, it becomes bank 1.
+                // The check is done on all 5 bits, so banks 20h, 40h
+                // and 60h can't be selected in ROM banking mode either
+                self.rom_bank = data & 0x1F;
+                if self.rom_bank == 0 { self.rom_bank = 1 };
+                self.update_banks();
+            },
+            // Switch RAM bank or upper bits of ROM bank
+            0x4000 ... 0x5FFF => {
+                self.ram_bank = data & 3;
+                self.update_banks();
+            },
+            // Mode
+            // 0: ROM banking mode (RAM bank 0 only, up to 2MB ROM)
+            // 1: RAM banking mode (4 RAM banks, bank 0 area is switchable too)
+            _ => {
+                self.is_ram_mode = data & 1 == 1;
+                self.update_banks();
+            },
+        }
+    }
+
+    fn rb_ram(&self, addr: u16) -> u8 {
+        if !self.enable_ram { return 0xFF }
+        read_ram(&self.ram, self.ram_offset + (addr & 0x1FFF) as usize)
+    }
+
+    fn wb_ram(&mut self, addr: u16, data: u8) {
+        if !self.enable_ram { return }
+        write_ram(&mut self.ram, self.ram_offset + (addr & 0x1FFF) as usize, data);
+    }
+
+    fn save_battery(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enable_ram);
+        w.bool(self.is_ram_mode);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enable_ram = try!(r.bool());
+        self.is_ram_mode = try!(r.bool());
+        self.rom_bank = try!(r.u8());
+        self.ram_bank = try!(r.u8());
+        try!(r.vec_into(&mut self.ram));
+        self.update_banks();
+        Ok(())
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mbc1_tests {
+    use super::*;
+    use super::super::banked_rom;
+
+    #[test]
+    fn rom_banking() {
+        // 2MB
+        let mut mbc = Mbc1::new(banked_rom(128), 0);
+
+        assert_eq!(mbc.rb_rom(0x4000), 1);
+        mbc.wb_rom(0x2000, 0x05);
+        assert_eq!(mbc.rb_rom(0x4000), 0x05);
+        // Bank 0 maps to bank 1
+        mbc.wb_rom(0x2000, 0x00);
+        assert_eq!(mbc.rb_rom(0x4000), 0x01);
+        // Upper bits
+        mbc.wb_rom(0x2000, 0x01);
+        mbc.wb_rom(0x4000, 0x03);
+        assert_eq!(mbc.rb_rom(0x4000), 0x61);
+        // 0x20 can't be selected, becomes 0x21
+        mbc.wb_rom(0x2000, 0x00);
+        mbc.wb_rom(0x4000, 0x01);
+        assert_eq!(mbc.rb_rom(0x4000), 0x21);
+        assert_eq!(mbc.rb_rom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn bank_wraps_to_rom_size() {
+        // 256KB
+        let mut mbc = Mbc1::new(banked_rom(16), 0);
+
+        mbc.wb_rom(0x2000, 0x13);
+        assert_eq!(mbc.rb_rom(0x4000), 0x03);
+    }
+
+    #[test]
+    fn mode1_remaps_bank0() {
+        let mut mbc = Mbc1::new(banked_rom(128), 0);
+
+        mbc.wb_rom(0x4000, 0x02);
+        assert_eq!(mbc.rb_rom(0x0000), 0x00);
+        mbc.wb_rom(0x6000, 0x01);
+        assert_eq!(mbc.rb_rom(0x0000), 0x40);
+        assert_eq!(mbc.rb_rom(0x4000), 0x41);
+    }
+
+    #[test]
+    fn ram_banking() {
+        // 32KB RAM
+        let mut mbc = Mbc1::new(banked_rom(4), RAM_BANK_SIZE * 4);
+
+        // Disabled by default
+        mbc.wb_ram(0xA000, 0x12);
+        assert_eq!(mbc.rb_ram(0xA000), 0xFF);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_ram(0xA000, 0x12);
+        assert_eq!(mbc.rb_ram(0xA000), 0x12);
+
+        // Mode 0 always uses RAM bank 0
+        mbc.wb_rom(0x4000, 0x02);
+        assert_eq!(mbc.rb_ram(0xA000), 0x12);
+
+        mbc.wb_rom(0x6000, 0x01);
+        assert_eq!(mbc.rb_ram(0xA000), 0x00);
+        mbc.wb_ram(0xA000, 0x34);
+        mbc.wb_rom(0x4000, 0x00);
+        assert_eq!(mbc.rb_ram(0xA000), 0x12);
+        mbc.wb_rom(0x4000, 0x02);
+        assert_eq!(mbc.rb_ram(0xA000), 0x34);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut rom = banked_rom(64);
+        for game in 0..4 {
+            let offset = game * 0x10 * ROM_BANK_SIZE + LOGO_OFFSET;
+            rom[offset..offset + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut mbc = Mbc1::new(rom, 0);
+        assert!(mbc.is_multicart);
+
+        // Second game: upper bits are shifted by 4, bit 4 of the lower ones is ignored
+        mbc.wb_rom(0x4000, 0x01);
+        mbc.wb_rom(0x2000, 0x12);
+        assert_eq!(mbc.rb_rom(0x4000), 0x12);
+        mbc.wb_rom(0x6000, 0x01);
+        assert_eq!(mbc.rb_rom(0x0000), 0x10);
+
+        // Plain 1MB MBC1 cart
+        let mut mbc = Mbc1::new(banked_rom(64), 0);
+        assert!(!mbc.is_multicart);
+        mbc.wb_rom(0x4000, 0x01);
+        mbc.wb_rom(0x2000, 0x12);
+        assert_eq!(mbc.rb_rom(0x4000), 0x32);
+    }
+}
@@ -0,0 +1,133 @@
+//
+//      MBC2: up to 256KB ROM, 512x4 bits of built-in RAM
+//
+
+use super::*;
+
+const RAM_SIZE: usize = 0x200;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    enable_ram: bool,
+    rom_bank: u8,
+    rom_bank_mask: usize,
+    rom_offset: usize,   // 4000-7FFF
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom_bank_mask: rom_bank_mask(&rom),
+            rom: rom,
+            // Built-in RAM, the header says there's none
+            ram: vec![0; RAM_SIZE],
+
+            enable_ram: false,
+            rom_bank: 1,
+            rom_offset: ROM_BANK_SIZE,
+        }
+    }
+
+    // Recalculates the ROM offset from the bank register
+    fn update_banks(&mut self) {
+        self.rom_offset = (self.rom_bank as usize & self.rom_bank_mask) * ROM_BANK_SIZE;
+        debug!("MBC2 banks. 4000: {:X}", self.rom_bank as usize & self.rom_bank_mask);
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn rb_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000 ... 0x3FFF => read_rom(&self.rom, addr as usize),
+            _ => read_rom(&self.rom, self.rom_offset + (addr & 0x3FFF) as usize),
+        }
+    }
+
+    // MBC2 has both of its registers in 0000-3FFF, bit 8 of the address
+    // selects which one gets written:
+    // 0: RAM enable (0Ah enables, anything else disables)
+    // 1: ROM bank number (4 bits, 0 becomes 1)
+    fn wb_rom(&mut self, addr: u16, data: u8) {
+        if addr >= 0x4000 { return }
+
+        if addr & 0x100 == 0 {
+            self.enable_ram = data & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = data & 0x0F;
+            if self.rom_bank == 0 { self.rom_bank = 1 };
+            self.update_banks();
+        }
+    }
+
+    // Only the lower nibble is stored, the upper one reads as 1s.
+    // The 512 bytes are mirrored across A000-BFFF.
+    fn rb_ram(&self, addr: u16) -> u8 {
+        if !self.enable_ram { return 0xFF }
+        0xF0 | self.ram[addr as usize & (RAM_SIZE - 1)]
+    }
+
+    fn wb_ram(&mut self, addr: u16, data: u8) {
+        if !self.enable_ram { return }
+        self.ram[addr as usize & (RAM_SIZE - 1)] = data & 0x0F;
+    }
+
+    fn save_battery(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enable_ram);
+        w.u8(self.rom_bank);
+        w.vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enable_ram = try!(r.bool());
+        self.rom_bank = try!(r.u8());
+        try!(r.vec_into(&mut self.ram));
+        self.update_banks();
+        Ok(())
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mbc2_tests {
+    use super::*;
+    use super::super::banked_rom;
+
+    #[test]
+    fn banking_and_ram() {
+        // 256KB ROM
+        let mut mbc = Mbc2::new(banked_rom(16));
+
+        // Bit 8 clear, RAM enable
+        mbc.wb_rom(0x2000, 0x05);
+        assert_eq!(mbc.rb_rom(0x4000), 0x01);
+        // Bit 8 set, ROM bank
+        mbc.wb_rom(0x2100, 0x05);
+        assert_eq!(mbc.rb_rom(0x4000), 0x05);
+        mbc.wb_rom(0x0100, 0x10);
+        assert_eq!(mbc.rb_rom(0x4000), 0x01);
+
+        assert_eq!(mbc.rb_ram(0xA000), 0xFF);
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_ram(0xA000, 0x5C);
+        assert_eq!(mbc.rb_ram(0xA000), 0xFC);
+        // Mirrored every 512 bytes
+        assert_eq!(mbc.rb_ram(0xA200), 0xFC);
+        assert_eq!(mbc.rb_ram(0xBE00), 0xFC);
+
+        mbc.wb_rom(0x3E00, 0x00);
+        assert_eq!(mbc.rb_ram(0xA000), 0xFF);
+    }
+}
@@ -0,0 +1,235 @@
+//
+//      MBC3: up to 2MB ROM, 32KB RAM and an optional Real Time Clock
+//
+
+use super::*;
+use rtc::{Rtc, RTC_S, RTC_DH};
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+
+    enable_ram: bool,    // Enables the RTC registers too
+    rom_bank: u8,
+    ram_bank: u8,        // 00h-03h select a RAM bank, 08h-0Ch a RTC register
+    rom_bank_mask: usize,
+
+    rom_offset: usize,   // 4000-7FFF
+    ram_offset: usize,   // A000-BFFF
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, rtc: Option<Rtc>) -> Mbc3 {
+        Mbc3 {
+            rom_bank_mask: rom_bank_mask(&rom),
+            rom: rom,
+            ram: vec![0; ram_size],
+            rtc: rtc,
+
+            enable_ram: false,
+            rom_bank: 1,
+            ram_bank: 0,
+
+            rom_offset: ROM_BANK_SIZE,
+            ram_offset: 0,
+        }
+    }
+
+    // True if A000-BFFF is mapped to a RTC register instead of RAM
+    fn is_rtc_selected(&self) -> bool {
+        self.ram_bank >= RTC_S
+    }
+
+    // Recalculates the ROM/RAM offsets from the registers
+    fn update_banks(&mut self) {
+        self.rom_offset = (self.rom_bank as usize & self.rom_bank_mask) * ROM_BANK_SIZE;
+        if !self.is_rtc_selected() {
+            self.ram_offset = (self.ram_bank as usize & 3) * RAM_BANK_SIZE;
+        }
+        debug!("MBC3 banks. 4000: {:02X}  A000: {:X}",
+            self.rom_bank as usize & self.rom_bank_mask, self.ram_bank);
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn rb_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000 ... 0x3FFF => read_rom(&self.rom, addr as usize),
+            _ => read_rom(&self.rom, self.rom_offset + (addr & 0x3FFF) as usize),
+        }
+    }
+
+    fn wb_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            // Enable external RAM and RTC if 0x0A was writtten. Disable them otherwise
+            0x0000 ... 0x1FFF => self.enable_ram = data & 0x0F == 0x0A,
+            // Switch ROM bank. All 7 bits are used, 0 still becomes 1
+            0x2000 ... 0x3FFF => {
+                self.rom_bank = data & 0x7F;
+                if self.rom_bank == 0 { self.rom_bank = 1 };
+                self.update_banks();
+            },
+            // Switch RAM bank or select a RTC register
+            0x4000 ... 0x5FFF => {
+                self.ram_bank = data & 0x0F;
+                self.update_banks();
+            },
+            // Latch Clock Data
+            _ => if let Some(ref mut rtc) = self.rtc { rtc.latch(data) },
+        }
+    }
+
+    fn rb_ram(&self, addr: u16) -> u8 {
+        if !self.enable_ram { return 0xFF }
+
+        if self.is_rtc_selected() {
+            match self.rtc {
+                Some(ref rtc) => rtc.rb(self.ram_bank),
+                None => 0xFF,
+            }
+        } else {
+            read_ram(&self.ram, self.ram_offset + (addr & 0x1FFF) as usize)
+        }
+    }
+
+    fn wb_ram(&mut self, addr: u16, data: u8) {
+        if !self.enable_ram { return }
+
+        if self.is_rtc_selected() {
+            if let Some(ref mut rtc) = self.rtc {
+                if self.ram_bank <= RTC_DH { rtc.wb(self.ram_bank, data) }
+            }
+        } else {
+            write_ram(&mut self.ram, self.ram_offset + (addr & 0x1FFF) as usize, data);
+        }
+    }
+
+    // RAM, followed by the RTC state if there's a clock
+    fn save_battery(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(ref rtc) = self.rtc {
+            data.extend(rtc.save());
+        }
+        data
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+        if let Some(ref mut rtc) = self.rtc {
+            // Saves from before the game had its clock set have no RTC data
+            if data.len() > self.ram.len() {
+                rtc.load(&data[self.ram.len()..]);
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enable_ram);
+        w.u8(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.vec(&self.ram);
+        match self.rtc {
+            Some(ref rtc) => w.vec(&rtc.save()),
+            None => w.vec(&[]),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enable_ram = try!(r.bool());
+        self.rom_bank = try!(r.u8());
+        self.ram_bank = try!(r.u8());
+        try!(r.vec_into(&mut self.ram));
+        let rtc_data = try!(r.vec());
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load(&rtc_data);
+        }
+        self.update_banks();
+        Ok(())
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mbc3_tests {
+    use super::*;
+    use super::super::banked_rom;
+    use rtc::{FakeClock, RTC_SAVE_SIZE};
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    #[test]
+    fn banking_and_rtc() {
+        // 2MB ROM, 32KB RAM
+        let time = Rc::new(Cell::new(0));
+        let rtc = Rtc::with_clock(Box::new(FakeClock(time.clone())));
+        let mut mbc = Mbc3::new(banked_rom(128), RAM_BANK_SIZE * 4, Some(rtc));
+
+        mbc.wb_rom(0x2000, 0x00);
+        assert_eq!(mbc.rb_rom(0x4000), 0x01);
+        mbc.wb_rom(0x2000, 0x75);
+        assert_eq!(mbc.rb_rom(0x4000), 0x75);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_rom(0x4000, 0x01);
+        mbc.wb_ram(0xA000, 0x56);
+        mbc.wb_rom(0x4000, 0x00);
+        assert_eq!(mbc.rb_ram(0xA000), 0x00);
+        mbc.wb_rom(0x4000, 0x01);
+        assert_eq!(mbc.rb_ram(0xA000), 0x56);
+
+        // Minutes register
+        time.set(125);
+        mbc.wb_rom(0x6000, 0x00);
+        mbc.wb_rom(0x6000, 0x01);
+        mbc.wb_rom(0x4000, 0x09);
+        assert_eq!(mbc.rb_ram(0xA000), 2);
+        mbc.wb_rom(0x4000, 0x08);
+        assert_eq!(mbc.rb_ram(0xA000), 5);
+
+        // RAM is untouched by RTC accesses
+        mbc.wb_rom(0x4000, 0x01);
+        assert_eq!(mbc.rb_ram(0xA000), 0x56);
+    }
+
+    #[test]
+    fn no_rtc() {
+        let mut mbc = Mbc3::new(banked_rom(4), RAM_BANK_SIZE, None);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_rom(0x4000, 0x08);
+        mbc.wb_ram(0xA000, 0x12);
+        assert_eq!(mbc.rb_ram(0xA000), 0xFF);
+        mbc.wb_rom(0x4000, 0x00);
+        assert_eq!(mbc.rb_ram(0xA000), 0x00);
+    }
+
+    #[test]
+    fn battery_with_rtc() {
+        let time = Rc::new(Cell::new(0));
+        let rtc = Rtc::with_clock(Box::new(FakeClock(time.clone())));
+        let mut mbc = Mbc3::new(banked_rom(4), RAM_BANK_SIZE, Some(rtc));
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_ram(0xA000, 0x34);
+        time.set(59);
+
+        let save = mbc.save_battery();
+        assert_eq!(save.len(), RAM_BANK_SIZE + RTC_SAVE_SIZE);
+
+        let rtc = Rtc::with_clock(Box::new(FakeClock(time.clone())));
+        let mut mbc = Mbc3::new(banked_rom(4), RAM_BANK_SIZE, Some(rtc));
+        time.set(60);
+        mbc.load_battery(&save);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        assert_eq!(mbc.rb_ram(0xA000), 0x34);
+        mbc.wb_rom(0x6000, 0x00);
+        mbc.wb_rom(0x6000, 0x01);
+        mbc.wb_rom(0x4000, 0x09);
+        assert_eq!(mbc.rb_ram(0xA000), 1);
+    }
+}
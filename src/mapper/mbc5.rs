@@ -0,0 +1,186 @@
+//
+//      MBC5: up to 8MB ROM, 128KB RAM and an optional rumble motor
+//
+
+use super::*;
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    enable_ram: bool,
+    rom_bank: u16,       // 9 bits
+    ram_bank: u8,
+    rom_bank_mask: usize,
+
+    has_rumble: bool,
+    is_rumbling: bool,
+
+    rom_offset: usize,   // 4000-7FFF
+    ram_offset: usize,   // A000-BFFF
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom_bank_mask: rom_bank_mask(&rom),
+            rom: rom,
+            ram: vec![0; ram_size],
+
+            enable_ram: false,
+            rom_bank: 1,
+            ram_bank: 0,
+
+            has_rumble: has_rumble,
+            is_rumbling: false,
+
+            rom_offset: ROM_BANK_SIZE,
+            ram_offset: 0,
+        }
+    }
+
+    // Recalculates the ROM/RAM offsets from the registers
+    fn update_banks(&mut self) {
+        self.rom_offset = (self.rom_bank as usize & self.rom_bank_mask) * ROM_BANK_SIZE;
+        self.ram_offset = self.ram_bank as usize * RAM_BANK_SIZE;
+        debug!("MBC5 banks. 4000: {:03X}  A000: {:X}",
+            self.rom_bank as usize & self.rom_bank_mask, self.ram_bank);
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn rb_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000 ... 0x3FFF => read_rom(&self.rom, addr as usize),
+            _ => read_rom(&self.rom, self.rom_offset + (addr & 0x3FFF) as usize),
+        }
+    }
+
+    fn wb_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            // Only enables RAM on exactly 0Ah
+            0x0000 ... 0x1FFF => self.enable_ram = data == 0x0A,
+            // Lower 8 bits of the ROM bank number. Bank 0 can be selected.
+            0x2000 ... 0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | data as u16;
+                self.update_banks();
+            },
+            // 9th bit of the ROM bank number
+            0x3000 ... 0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 1) << 8);
+                self.update_banks();
+            },
+            // 16 RAM banks. On rumble carts bit 3 drives the motor
+            // instead, leaving 8 banks.
+            0x4000 ... 0x5FFF => {
+                if self.has_rumble {
+                    let rumble = data & 0x08 != 0;
+                    if rumble != self.is_rumbling {
+                        debug!("Rumble {}", if rumble {"on"} else {"off"});
+                    }
+                    self.is_rumbling = rumble;
+                    self.ram_bank = data & 0x07;
+                } else {
+                    self.ram_bank = data & 0x0F;
+                }
+                self.update_banks();
+            },
+            _ => {},
+        }
+    }
+
+    fn rb_ram(&self, addr: u16) -> u8 {
+        if !self.enable_ram { return 0xFF }
+        read_ram(&self.ram, self.ram_offset + (addr & 0x1FFF) as usize)
+    }
+
+    fn wb_ram(&mut self, addr: u16, data: u8) {
+        if !self.enable_ram { return }
+        write_ram(&mut self.ram, self.ram_offset + (addr & 0x1FFF) as usize, data);
+    }
+
+    fn save_battery(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enable_ram);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.is_rumbling);
+        w.vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enable_ram = try!(r.bool());
+        self.rom_bank = try!(r.u16());
+        self.ram_bank = try!(r.u8());
+        self.is_rumbling = try!(r.bool());
+        try!(r.vec_into(&mut self.ram));
+        self.update_banks();
+        Ok(())
+    }
+
+    fn is_rumbling(&self) -> bool {
+        self.is_rumbling
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mbc5_tests {
+    use super::*;
+    use super::super::banked_rom;
+
+    #[test]
+    fn banking() {
+        // 8MB ROM, 128KB RAM
+        let mut rom = banked_rom(512);
+        rom[0x1FF * ROM_BANK_SIZE + 1] = 0xAB;
+        let mut mbc = Mbc5::new(rom, RAM_BANK_SIZE * 16, false);
+
+        // Bank 0 is selectable
+        mbc.wb_rom(0x2000, 0x00);
+        assert_eq!(mbc.rb_rom(0x4000), 0x00);
+        mbc.wb_rom(0x2000, 0xFF);
+        mbc.wb_rom(0x3000, 0x01);
+        assert_eq!(mbc.rb_rom(0x4001), 0xAB);
+        mbc.wb_rom(0x3000, 0x00);
+        assert_eq!(mbc.rb_rom(0x4000), 0xFF);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_rom(0x4000, 0x0F);
+        mbc.wb_ram(0xA000, 0x42);
+        mbc.wb_rom(0x4000, 0x07);
+        assert_eq!(mbc.rb_ram(0xA000), 0x00);
+        mbc.wb_rom(0x4000, 0x0F);
+        assert_eq!(mbc.rb_ram(0xA000), 0x42);
+        assert!(!mbc.is_rumbling());
+    }
+
+    #[test]
+    fn rumble() {
+        // 32KB RAM
+        let mut mbc = Mbc5::new(banked_rom(4), RAM_BANK_SIZE * 4, true);
+
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_rom(0x4000, 0x01);
+        mbc.wb_ram(0xA000, 0x42);
+        assert!(!mbc.is_rumbling());
+
+        // Bit 3 turns on the motor and doesn't change the RAM bank
+        mbc.wb_rom(0x4000, 0x09);
+        assert!(mbc.is_rumbling());
+        assert_eq!(mbc.rb_ram(0xA000), 0x42);
+
+        mbc.wb_rom(0x4000, 0x01);
+        assert!(!mbc.is_rumbling());
+    }
+}
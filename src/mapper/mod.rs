@@ -0,0 +1,161 @@
+//
+//      Cartridge Memory Bank Controllers
+//
+
+pub mod rom_only;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+
+pub use self::rom_only::RomOnly;
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+
+use std::io;
+
+use rtc::Rtc;
+use state::{StateWriter, StateReader};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// The hardware in a cartridge that sits between the CPU and the cartridge's
+// ROM/RAM. Memory hands it every access to 0000-7FFF and A000-BFFF.
+pub trait Mapper {
+    // Read from ROM (0000-7FFF)
+    fn rb_rom(&self, addr: u16) -> u8;
+    // Write to the controller's registers (0000-7FFF)
+    fn wb_rom(&mut self, addr: u16, data: u8);
+
+    // Read from external RAM (A000-BFFF)
+    fn rb_ram(&self, addr: u16) -> u8;
+    // Write to external RAM (A000-BFFF)
+    fn wb_ram(&mut self, addr: u16, data: u8);
+
+    // Contents of the battery backed memory, to be persisted between runs.
+    // Empty if there's nothing to save.
+    fn save_battery(&self) -> Vec<u8>;
+    // Restore what save_battery() returned
+    fn load_battery(&mut self, data: &[u8]);
+
+    // Write the banking registers and RAM for a save state. The ROM isn't
+    // included, states are loaded on top of the same cartridge.
+    fn save_state(&self, w: &mut StateWriter);
+    // Restore what save_state() wrote
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+
+    // Whether a rumble motor is currently on
+    fn is_rumbling(&self) -> bool { false }
+}
+
+// Creates the mapper for the cartridge type and RAM size in the ROM's header.
+// Fails for cartridge types that aren't supported.
+pub fn from_header(rom: Vec<u8>, cartridge_type: u8, ram_size: u8) -> io::Result<Box<Mapper>> {
+    let ram_size = ext_ram_size(ram_size);
+
+    let mapper: Box<Mapper> = match cartridge_type {
+        0x00 | 0x08 | 0x09 => Box::new(RomOnly::new(rom, ram_size)),
+        0x01 ... 0x03 => Box::new(Mbc1::new(rom, ram_size)),
+        0x05 ... 0x06 => Box::new(Mbc2::new(rom)),
+        // MBC3+TIMER+BATTERY and MBC3+TIMER+RAM+BATTERY have a RTC
+        0x0F ... 0x10 => Box::new(Mbc3::new(rom, ram_size, Some(Rtc::new()))),
+        0x11 ... 0x13 => Box::new(Mbc3::new(rom, ram_size, None)),
+        0x19 ... 0x1B => Box::new(Mbc5::new(rom, ram_size, false)),
+        // MBC5+RUMBLE, MBC5+RUMBLE+RAM and MBC5+RUMBLE+RAM+BATTERY
+        0x1C ... 0x1E => Box::new(Mbc5::new(rom, ram_size, true)),
+
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Unsupported MBC, cartridge type: {:02X}", cartridge_type))),
+    };
+    info!("Cartridge type: {:02X}. External RAM: {} bytes", cartridge_type, ram_size);
+
+    Ok(mapper)
+}
+
+// Size in bytes of the external RAM for the header's RAM size value
+pub fn ext_ram_size(ram_size: u8) -> usize {
+    match ram_size {
+        0x01 => 0x800,
+        0x02 => RAM_BANK_SIZE,
+        0x03 => RAM_BANK_SIZE * 4,
+        0x04 => RAM_BANK_SIZE * 16,
+        0x05 => RAM_BANK_SIZE * 8,
+        _ => 0,
+    }
+}
+
+// Bank numbers wrap around the ROM size, which is always a power of 2
+fn rom_bank_mask(rom: &[u8]) -> usize {
+    let rom_banks = (rom.len() / ROM_BANK_SIZE).max(2);
+    rom_banks.next_power_of_two() - 1
+}
+
+// Reads from the ROM, open bus past its end
+fn read_rom(rom: &[u8], offset: usize) -> u8 {
+    match rom.get(offset) {
+        Some(&val) => val,
+        None => 0xFF,
+    }
+}
+
+// Reads from RAM at the bank offset, mirrored if the RAM is smaller than the
+// banked area. Open bus if there's no RAM.
+fn read_ram(ram: &[u8], offset: usize) -> u8 {
+    if ram.is_empty() { 0xFF } else { ram[offset % ram.len()] }
+}
+
+fn write_ram(ram: &mut [u8], offset: usize, data: u8) {
+    if !ram.is_empty() {
+        let i = offset % ram.len();
+        ram[i] = data;
+    }
+}
+
+// Restores RAM from a battery save, ignoring anything past the RAM size
+fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+// Builds a ROM where the first byte of every bank is its bank number
+#[cfg(test)]
+fn banked_rom(banks: usize) -> Vec<u8> {
+    let mut rom = vec![0u8; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    rom
+}
+
+#[cfg(test)]
+mod mapper_tests {
+    use super::*;
+
+    #[test]
+    fn battery_round_trip() {
+        let mut mbc = from_header(banked_rom(4), 0x03, 0x02).unwrap();
+        mbc.wb_rom(0x0000, 0x0A);
+        mbc.wb_ram(0xA123, 0x45);
+
+        let save = mbc.save_battery();
+        assert_eq!(save.len(), RAM_BANK_SIZE);
+
+        let mut mbc = from_header(banked_rom(4), 0x03, 0x02).unwrap();
+        mbc.load_battery(&save);
+        mbc.wb_rom(0x0000, 0x0A);
+        assert_eq!(mbc.rb_ram(0xA123), 0x45);
+    }
+
+    #[test]
+    fn unsupported_type() {
+        // HuC1
+        assert!(from_header(banked_rom(4), 0xFF, 0x02).is_err());
+    }
+}
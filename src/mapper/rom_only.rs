@@ -0,0 +1,53 @@
+//
+//      No MBC: 32KB ROM, optionally up to 8KB of RAM
+//
+
+use super::*;
+
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom: rom,
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn rb_rom(&self, addr: u16) -> u8 {
+        read_rom(&self.rom, addr as usize)
+    }
+
+    // Ignore writes, there's nothing to handle them
+    fn wb_rom(&mut self, addr: u16, data: u8) {}
+
+    // Without an MBC, RAM (if any) is always accessible
+    fn rb_ram(&self, addr: u16) -> u8 {
+        read_ram(&self.ram, (addr & 0x1FFF) as usize)
+    }
+
+    fn wb_ram(&mut self, addr: u16, data: u8) {
+        write_ram(&mut self.ram, (addr & 0x1FFF) as usize, data);
+    }
+
+    fn save_battery(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_battery(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.vec_into(&mut self.ram)
+    }
+}
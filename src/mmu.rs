@@ -20,26 +20,20 @@
 
 #![allow(dead_code)]
 
-use piston::window::Window;
-use piston_window::PistonWindow;
-
 use timer::Timer;
 use gpu::Gpu;
+use apu::Apu;
 use gpu;
 use input::Input;
+use mapper::{self, Mapper, RomOnly};
+use state::{StateWriter, StateReader};
 
-#[derive(PartialEq, Eq, Debug)]
-enum Mbc {
-    RomOnly,
-    Mbc1,
-    Mbc2,
-    Mbc3,
-    Mbc4,
-    Mbc5,
-    Unknown,
-}
+use std::io;
+use std::mem;
 
 const MEM_SIZE: usize = 0xFFFF + 1;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
 
 pub struct Memory {
     // Interrupt flags, http://problemkaputt.de/pandocs.htm#interrupts
@@ -49,53 +43,79 @@ pub struct Memory {
 
     raw_mem: Box<[u8; MEM_SIZE]>,
 
-    pub rom_loaded: Vec<u8>,
+    // Work RAM. C000-CFFF is always bank 0, D000-DFFF is bank 1 on the DMG
+    // and the one selected by SVBK (0xff70) on the CGB.
+    wram: Box<[u8; WRAM_BANK_SIZE * WRAM_BANKS]>,
+    svbk: u8,
+
+    // 0xff4d - KEY1 - CGB speed switch. Armed here, done by the STOP
+    // instruction.
+    pub double_speed: bool,
+    speed_switch_armed: bool,
 
     pub timer: Box<Timer>,
     pub gpu: Box<Gpu>,
+    pub apu: Box<Apu>,
     pub input: Input,
 
-    mbc: Mbc,
-    cart_type: u8,
-    enable_ext_ram: bool,
-    is_ram_mode: bool,   // true -> RAM expansion mode, else ROM mode
-    rom_bank: u8,
-    rom_offset: u16,
-    ram_bank: u8,
-    ram_offset: u16,
+    // Everything written to SB (0xff01). There's no link cable, but test ROMs
+    // print their results through it.
+    serial: Vec<u8>,
+
+    // Cartridge ROM, RAM and bank controller
+    mapper: Box<Mapper>,
 
     // OAM DMA stuff
     pub is_dma: bool,
     dma_left: usize,
     dma_value: u8,
+
+    // 0xff51-0xff55 - CGB VRAM DMA. Copies blocks of 16 bytes to VRAM, either
+    // all at once (general purpose) or one every HBlank. The CPU waits while
+    // a block is copied.
+    hdma_src: u16,
+    hdma_dst: u16,
+    hdma_len: u8,                   // blocks left minus one, as read from HDMA5
+    pub is_hdma: bool,              // an HBlank DMA is running
+    hdma_stall: u32,                // CPU cycles the copies took
+
+    // M-cycles run by the CPU in the current instruction
+    ticks: u32,
 }
 
 impl Memory {
     // Allocate a 64k byte array and zero initialize it
     // This is all the system's RAM
-    pub fn new<W: Window>(window: &PistonWindow<W>) -> Memory {
+    pub fn new() -> Memory {
         let mut mem = Memory {
             if_: 1u8,
             ie_: 0u8,
             raw_mem: Box::new([0u8; MEM_SIZE]),
-            rom_loaded: Vec::new(),
+            wram: Box::new([0u8; WRAM_BANK_SIZE * WRAM_BANKS]),
+            svbk: 0,
+            double_speed: false,
+            speed_switch_armed: false,
 
             timer: Box::new(Timer::new()),
-            gpu: Box::new(Gpu::new(window)),
+            gpu: Box::new(Gpu::new()),
+            apu: Box::new(Apu::new()),
             input: Input::new(),
+            serial: Vec::new(),
 
-            mbc: Mbc::Unknown,
-            cart_type: 0,
-            enable_ext_ram: false,
-            is_ram_mode: false,
-            rom_bank: 0,
-            rom_offset: 0x4000,
-            ram_bank: 0,
-            ram_offset: 0x0000,
+            // No cartridge inserted
+            mapper: Box::new(RomOnly::new(Vec::new(), 0)),
 
             is_dma: false,
             dma_left: 0,
             dma_value: 0,
+
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_len: 0x7F,
+            is_hdma: false,
+            hdma_stall: 0,
+
+            ticks: 0,
         };
         mem.power_on();
         mem.timer.reset_bios_skip();
@@ -104,21 +124,16 @@ impl Memory {
     }
 
     // Copies VRAM from rom to the gpu's vrambanks
-    pub fn copy_vram(&mut self) {
+    pub fn copy_vram(&mut self, rom: &[u8]) {
         // TODO: Make faster?
         const VRAM_START: u16 = 0x8000;
         const VRAM_SIZE: u16 = 0x2000;
         for addr in 0..VRAM_SIZE {
-            self.gpu.vrambank[addr as usize] = self.rom_loaded[(addr + VRAM_START) as usize];
+            self.gpu.vrambank[addr as usize] = rom[(addr + VRAM_START) as usize];
             //self.gpu.vrambanks[1][addr as usize] = self.rom_loaded[(addr + VRAM_EXT_START) as usize];
         }
     }
 
-    pub fn copy_rom(&mut self) {
-        // Copy ROM data to memory
-        self.raw_mem[0x0000..0x7FFF].copy_from_slice(&self.rom_loaded[0x0000..0x7FFF]);
-    }
-
     pub fn power_on(&mut self) {
         // From http://problemkaputt.de/pandocs.htm#powerupsequence
         self.wb(0xff05, 0x00); // TIMA
@@ -155,18 +170,115 @@ impl Memory {
         self.wb(0xffff, 0x00); // IE
 
     }
-    pub fn set_rom(&mut self, rom: Vec<u8>) {
-        self.rom_loaded = rom;
+    // Runs as a CGB, for cartridges that support it
+    pub fn set_cgb_mode(&mut self) {
+        self.gpu.is_cgb = true;
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.gpu.is_cgb
+    }
+
+    // Switches between normal and double speed, if KEY1 asked for it. Called
+    // on STOP, returns whether the speed changed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed { return false }
+
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        info!("Switched to {} speed", if self.double_speed {"double"} else {"normal"});
+        true
+    }
+
+    // Inserts a cartridge, picking the mapper from the header values. Fails
+    // if the cartridge type isn't supported.
+    pub fn load_cartridge(&mut self, rom: Vec<u8>, cartridge_type: u8, ram_size: u8) -> io::Result<()> {
+        self.mapper = try!(mapper::from_header(rom, cartridge_type, ram_size));
+        Ok(())
+    }
+
+    pub fn set_mapper(&mut self, mapper: Box<Mapper>) {
+        self.mapper = mapper;
+    }
+
+    pub fn get_mapper(&self) -> &Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn get_mapper_mut(&mut self) -> &mut Mapper {
+        self.mapper.as_mut()
     }
     // Borrow
     // pub fn borrow_rom_header(&mut self, header: &CartridgeHeader) {
     //  self.rom_header = Some(header);
     // }
 
+    pub fn serial_output(&self) -> &[u8] {
+        &self.serial
+    }
+
     pub fn get_timers(&self) -> &Timer {
         &self.timer.as_ref()
     }
 
+    // Everything but the cartridge ROM and the joypad, which follows the
+    // player's keys and not the state
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.if_);
+        w.u8(self.ie_);
+        w.bytes(&*self.raw_mem);
+        w.bytes(&*self.wram);
+        w.u8(self.svbk);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+
+        w.bool(self.is_dma);
+        w.u32(self.dma_left as u32);
+        w.u8(self.dma_value);
+
+        w.u16(self.hdma_src);
+        w.u16(self.hdma_dst);
+        w.u8(self.hdma_len);
+        w.bool(self.is_hdma);
+        w.u32(self.hdma_stall);
+
+        self.timer.save_state(w);
+        self.apu.save_state(w);
+        self.gpu.save_state(w);
+        self.mapper.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.if_ = try!(r.u8());
+        self.ie_ = try!(r.u8());
+        try!(r.bytes(&mut *self.raw_mem));
+        try!(r.bytes(&mut *self.wram));
+        self.svbk = try!(r.u8());
+        self.double_speed = try!(r.bool());
+        self.speed_switch_armed = try!(r.bool());
+
+        self.is_dma = try!(r.bool());
+        self.dma_left = try!(r.u32()) as usize;
+        self.dma_value = try!(r.u8());
+
+        self.hdma_src = try!(r.u16());
+        self.hdma_dst = try!(r.u16());
+        self.hdma_len = try!(r.u8());
+        self.is_hdma = try!(r.bool());
+        self.hdma_stall = try!(r.u32());
+
+        try!(self.timer.load_state(r));
+        try!(self.apu.load_state(r));
+        try!(self.gpu.load_state(r));
+        self.mapper.load_state(r)
+    }
+
+    // Whether the cartridge's rumble motor is currently on. Always false for
+    // carts without one.
+    pub fn is_rumbling(&self) -> bool {
+        self.mapper.is_rumbling()
+    }
+
     // Private members
 
     fn read_byte_raw(&self, addr: u16) -> u8 {
@@ -203,31 +315,35 @@ impl Memory {
         self.raw_mem[addr + 1] = (data & 0x00FF) as u8;
     }
 
+    // Index into wram for an address in C000-DFFF. Bank 0 in SVBK selects
+    // bank 1 too.
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = addr as usize - 0xC000;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            let bank = (self.svbk as usize).max(1);
+            bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
     // Public members
 
-    // Read Byte
-    // TODO: add 4 to total_cycles for cycle accuracy (not that simple)
+    // Read Byte. Takes no time, the CPU goes through rb_cycle()
     pub fn rb(&mut self, addr: u16) -> u8 {
         //self.debug_print_addr(addr, true);
-        //self.timer.step(4, &mut self.if_);
         match addr {
-            // ROM (switched bank)
-            0x4000 ... 0x7FFF => {
-                // if addr == 0x4000 {
-                //     info!("read bank: {}  offset: {:04X}  is_ram: {}  addr & 0x3FFF: {:04X}  final: {:04X}", self.rom_bank, self.rom_offset, self.is_ram_mode, addr & 0x3FFF, self.rom_offset + (addr & 0x3FFF));
-                // }
-                self.read_byte_raw(self.rom_offset + (addr & 0x3FFF))
-            },
+            // ROM, let the cartridge handle it
+            0x0000 ... 0x7FFF => self.mapper.rb_rom(addr),
             // VRAM so let the gpu handle it
             0x8000 ... 0x9FFF => self.gpu.rb_vram(addr),
             // External RAM
-            0xA000 ... 0xBFFF => if self.enable_ext_ram {
-                self.read_byte_raw(self.ram_offset + (addr & 0x1FFF))
-                } else {
-                    0xFF
-                },
-            // Mirrored memory
-            0xE000 ... 0xFDFF => self.read_byte_raw(addr - 0x2000),
+            0xA000 ... 0xBFFF => self.mapper.rb_ram(addr),
+            // Work RAM and its mirror
+            0xC000 ... 0xDFFF => self.wram[self.wram_index(addr)],
+            0xE000 ... 0xFDFF => self.wram[self.wram_index(addr - 0x2000)],
+            // Sprite Attribute Table
+            0xFE00 ... 0xFE9F => self.gpu.oam[(addr - 0xFE00) as usize],
             0xFEA0 ... 0xFEFF => 0xFF, // { warn!("Unusable memory accessed"); 0xFF },
             0xFF00 ... 0xFF79 => self.ioreg_rb(addr),
 
@@ -243,6 +359,52 @@ impl Memory {
         }
     }
 
+    // Runs the rest of the machine for one M-cycle. That's 4 clocks for the
+    // timer, which follows the CPU's speed, and 4 or 2 in double speed for
+    // the others.
+    pub fn tick(&mut self) {
+        let clocks = if self.double_speed {2} else {4};
+        self.timer.step(4, &mut self.if_);
+        self.gpu.step(clocks, &mut self.if_);
+        self.apu.step(clocks);
+        if self.is_dma {
+            self.handle_dma_transfer();
+        }
+        self.ticks += 1;
+    }
+
+    // CPU bus accesses. They take an M-cycle each and happen at the end of
+    // it, so the timer, GPU and DMA see them on the right clock.
+    pub fn rb_cycle(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.rb(addr)
+    }
+
+    pub fn wb_cycle(&mut self, addr: u16, data: u8) {
+        self.tick();
+        self.wb(addr, data);
+    }
+
+    // Low byte first
+    pub fn rw_cycles(&mut self, addr: u16) -> u16 {
+        (self.rb_cycle(addr) as u16) |
+        (self.rb_cycle(addr.wrapping_add(1)) as u16) << 8
+    }
+
+    pub fn ww_cycles(&mut self, addr: u16, data: u16) {
+        self.wb_cycle(addr, data as u8);
+        self.wb_cycle(addr.wrapping_add(1), (data >> 8) as u8);
+    }
+
+    // Runs whatever is left of an instruction that takes `cycles` clocks, the
+    // cycles it didn't access the bus in. Returns the clocks it really took.
+    pub fn finish_cycles(&mut self, cycles: u32) -> u32 {
+        while self.ticks * 4 < cycles {
+            self.tick();
+        }
+        mem::replace(&mut self.ticks, 0) * 4
+    }
+
     // Read word
     pub fn rw(&mut self, addr: u16) -> u16 {
         assert!(addr <= 0xFFFF - 1,
@@ -252,62 +414,18 @@ impl Memory {
         (self.rb(addr + 1) as u16) << 8
     }
 
-    // Write byte
+    // Write byte. Takes no time, the CPU goes through wb_cycle()
     pub fn wb(&mut self, addr: u16, data: u8) {
         //self.debug_print_addr(addr, false);
-        //self.timer.step(4, &mut self.if_);
         match addr {
-            // Enable external RAM if 0x0A was writtten. Disable it otherwise
-            0x0000 ... 0x1FFF => if self.cart_type == 2 || self.cart_type == 3 {
-                self.enable_ext_ram = data & 0x0F == 0x0A;
-            },
-            // Switch ROM bank
-            0x2000 ... 0x3FFF => {
-                match self.mbc {
-                    Mbc::RomOnly => {}  // Ignore writes when no MBC (nothing to handle them)
-                    Mbc::Mbc1 => {
-                        let mut data_lower = data & 0x1F;
-                        if data_lower == 0 { data_lower = 1 };
-
-                        self.rom_bank = self.rom_bank & 0x60 + data_lower;
-                        self.rom_offset = self.rom_bank as u16 * 0x4000;
-                    },
-                    //Mbc::Mbc2 => {},
-                    //Mbc::Mbc3 => {},
-                    //Mbc::Mbc4 => {},
-                    //Mbc::Unknown => {},
-                    _ => panic!("Unsupported MBC {:?}", self.mbc),
-                }
-            }
-            // Switch ROM bank "set" {1-31}-{97-127} and RAM bank
-            0x4000 ... 0x5FFF => {
-                match self.mbc {
-                    Mbc::RomOnly => {},  // Ignore writes when no MBC (nothing to handle them)
-                    Mbc::Mbc1 => {
-                        if self.is_ram_mode {
-                            // RAM mode: Set bank
-                            self.ram_bank = data & 3;
-                            self.ram_offset = self.ram_bank as u16 * 0x2000;
-                            info!("Switch RAM bank. bank: {}  offset: {:04X}", self.ram_bank, self.ram_offset);
-                        } else {
-                            // ROM mode: Set high bits of bank
-                            self.rom_bank = self.rom_bank & 0x1F + ((data & 3) << 5);
-                            self.rom_offset = self.rom_bank as u16 * 0x4000;
-                            info!("Switch ROM bank. bank: {}  offset: {:04X}", self.rom_bank, self.rom_offset);
-                        }
-                    },
-                    _ => panic!("Unsupported MBC {:?}", self.mbc),
-                }
-            }
-            // Mode
-            // 0: ROM mode (no RAM banks, up to 2MB ROM)
-            // 1: RAM mode (4 RAM banks, up to 512kB ROM)
-            0x6000 ... 0x7FFF => self.is_ram_mode = data & 1 == 1,
-            0xA000 ... 0xBFFF => if self.enable_ext_ram {
-                self.write_byte_raw(addr, data);
-            },
-            // Mirrored memory
-            0xE000 ... 0xFDFF => self.write_byte_raw(addr - 0x2000, data),
+            // MBC registers, let the cartridge handle it
+            0x0000 ... 0x7FFF => self.mapper.wb_rom(addr, data),
+            // External RAM
+            0xA000 ... 0xBFFF => self.mapper.wb_ram(addr, data),
+            // Work RAM and its mirror
+            0xC000 ... 0xDFFF => { let i = self.wram_index(addr); self.wram[i] = data; }
+            0xE000 ... 0xFDFF => { let i = self.wram_index(addr - 0x2000); self.wram[i] = data; }
+            0xFE00 ... 0xFE9F => self.gpu.oam[(addr - 0xFE00) as usize] = data,
             0xFEA0 ... 0xFEFF => debug!("Unusable memory written to"),
             // VRAM so let the gpu handle it
             0x8000 ... 0x9FFF => self.gpu.wb_vram(addr, data),
@@ -352,6 +470,8 @@ impl Memory {
                     _ => 0xFF,
                 }
             }
+            // Sound Registers and Wave RAM (0xFF1x-0xFF3x)
+            0x1 ... 0x3 => self.apu.rb(addr),
             // Video I/O Registers (0xFF4x)
             0x4 => {
                 match addr & 0xF {
@@ -359,17 +479,26 @@ impl Memory {
                         //debug!("gpu_rb {:x}", addr);
                         self.gpu.rb(addr)
                     },
+                    0xD if self.is_cgb() => {
+                        0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+                    },
                     _ => 0xFF//self.read_byte_raw(addr),
                 }
             }
+            // CGB VRAM DMA (0xFF51-0xFF55), only the length can be read back
+            0x5 if addr == 0xFF55 && self.is_cgb() => {
+                if self.is_hdma {self.hdma_len} else {0x80 | self.hdma_len}
+            }
+            // CGB palettes (0xFF68-0xFF6B)
+            0x6 => self.gpu.rb(addr),
+            // CGB WRAM bank (0xFF70)
+            0x7 if addr == 0xFF70 && self.is_cgb() => 0xF8 | self.svbk,
             _ => 0xFF//self.read_byte_raw(addr),
         }
     }
 
     fn ioreg_wb(&mut self, addr: u16, data: u8) {
         use std::str;
-        use std::io::prelude::*;
-        use std::fs::OpenOptions;
 
         //debug!("ioreg_wb {:x} {:x}", addr, data);
         match (addr >> 4) & 0xF {
@@ -380,25 +509,13 @@ impl Memory {
                     0x0 => self.input.wb(data),
                     0x1 => {
                         info!("Serial data transfer in address {:04X}, data {}", addr, data as char);
-
-                        // TODO: Maybe open at constructor like trace_log.txt
-                        // Open a file in write-only mode, returns `io::Result<File>`
-                        let mut file = OpenOptions::new()
-                            .append(true)
-                            .create(true)
-                            .open("serial_out.txt")
-                            .unwrap();
-
-                        file.write(&[data]).unwrap();
+                        self.serial.push(data);
                     }
                     0x2 => {/* Serial transfer start */}
-                    0x4 => { self.timer.div = 0; }
-                    0x5 => { self.timer.tima = data; }
-                    0x6 => { self.timer.tma = data; }
-                    0x7 => {
-                        self.timer.tac = data & 0b111;
-                        self.timer.update();
-                    }
+                    0x4 => self.timer.reset_div(&mut self.if_),
+                    0x5 => self.timer.write_tima(data),
+                    0x6 => self.timer.write_tma(data),
+                    0x7 => self.timer.set_tac(data, &mut self.if_),
                     0xf => { self.if_ = data; }
                     _ => {
                         warn!("Unhandled ioreg_wb address {:04X} written to. data: {:02X}", addr, data);
@@ -406,62 +523,54 @@ impl Memory {
                     }
                 }
             }
+            // Sound Registers and Wave RAM (0xFF1x-0xFF3x)
+            0x1 ... 0x3 => self.apu.wb(addr, data),
             // Video I/O Registers (0xFF4x)
             0x4 => {
                 match addr & 0xF {
-                    0...3 | 5 | 7...0xB => {
+                    0...3 | 5 | 7...0xB | 0xF => {
                         let dt = self.gpu.wb(addr, data);
                         //debug!("gpu_wb {:x} {:x}", addr, data);
                         dt
                     },
+                    0xD => {
+                        if self.is_cgb() {
+                            self.speed_switch_armed = data & 1 != 0;
+                        }
+                    },
                     // Write to LY normally resets it, but it leads
                     // to challenging timings so just do nothing
                     4 => {},
-                    6 => {
-                            self.start_dma_transfer(data);
-                            self.timer.step(4, &mut self.if_);
-                        }
+                    6 => self.start_dma_transfer(data),
                     _ => self.write_byte_raw(addr, data)
                 }
             }
+            // CGB VRAM DMA (0xFF51-0xFF55). The source is 16 byte aligned
+            // anywhere, the destination 16 byte aligned in VRAM.
+            0x5 if self.is_cgb() => {
+                match addr & 0xF {
+                    0x1 => self.hdma_src = (self.hdma_src & 0x00F0) | (data as u16) << 8,
+                    0x2 => self.hdma_src = (self.hdma_src & 0xFF00) | (data & 0xF0) as u16,
+                    0x3 => self.hdma_dst = (self.hdma_dst & 0x00F0) | ((data & 0x1F) as u16) << 8,
+                    0x4 => self.hdma_dst = (self.hdma_dst & 0x1F00) | (data & 0xF0) as u16,
+                    0x5 => self.start_hdma(data),
+                    _ => self.write_byte_raw(addr, data),
+                }
+            }
+            // CGB palettes (0xFF68-0xFF6B)
+            0x6 => self.gpu.wb(addr, data),
+            // CGB WRAM bank (0xFF70)
+            0x7 if addr == 0xFF70 => {
+                if self.is_cgb() {
+                    self.svbk = data & 0x07;
+                }
+            }
             _ => {
                 self.write_byte_raw(addr, data);
             }
         }
     }
 
-    pub fn find_mbc(&mut self, cartridge_type: u8) {
-        self.cart_type = cartridge_type;
-
-        match cartridge_type {
-            2 | 3 | 8 | 9 | 0xC | 0xD | 0x10 |
-            0x12 | 0x13 | 0x16 | 0x17 | 0x1A |
-            0x1B | 0x1D | 0x1E | 0xFF => {
-                self.enable_ext_ram = true;
-            }
-            _ => {}
-        };
-
-        self.mbc = match cartridge_type {
-            0x00 => Mbc::RomOnly,
-            0x01 ... 0x03 => Mbc::Mbc1,
-            0x05 ... 0x06 => Mbc::Mbc2,
-            0x0F ... 0x13 => Mbc::Mbc3,
-            0x15 ... 0x17 => Mbc::Mbc4,
-            0x19 ... 0x1E => Mbc::Mbc5,
-
-            _ => Mbc::Unknown,
-        };
-
-        // Only support MBC1 for now
-        match self.mbc {
-            Mbc::RomOnly | Mbc::Mbc1 => {},
-            _ => panic!("Unsupported MBC: {:?}", self.mbc),
-        };
-        info!("Mbc: {:?}. External RAM: {}", self.mbc, self.enable_ext_ram);
-    }
-
-
     fn debug_print_addr(&self, addr: u16, read: bool) {
         debug!("{} {:04X} in {}", if read {"Read from"} else {"Write to"}, addr,
 
@@ -492,8 +601,6 @@ impl Memory {
         self.is_dma = true;
         self.dma_left = gpu::OAM_SIZE;
         self.dma_value = val;
-
-        self.timer.step(4, &mut self.if_);
     }
 
     pub fn handle_dma_transfer(&mut self) {
@@ -507,11 +614,60 @@ impl Memory {
 
         self.gpu.oam[low_byte] = self.rb(high_byte | low_byte as u16 );
 
-        self.timer.step(4, &mut self.if_);
-
         // println!("{:04X} becomes {:02X}",
         // high_byte | low_byte as u16, self.rb(high_byte | low_byte as u16));
     }
+
+    // HDMA5 write. With bit 7 set the blocks are copied one per HBlank,
+    // otherwise all of them right away. Writing with bit 7 clear while an
+    // HBlank DMA runs stops it instead, HDMA5 keeps the blocks left.
+    fn start_hdma(&mut self, val: u8) {
+        if self.is_hdma && val & 0x80 == 0 {
+            debug!("HBlank DMA stopped, {} blocks left", self.hdma_len as u32 + 1);
+            self.is_hdma = false;
+            return;
+        }
+
+        self.hdma_len = val & 0x7F;
+        debug!("{} DMA of {} bytes from 0x{:04X} to 0x{:04X}",
+               if val & 0x80 != 0 {"HBlank"} else {"General purpose"},
+               (self.hdma_len as u32 + 1) * 16, self.hdma_src, 0x8000 | self.hdma_dst);
+
+        if val & 0x80 != 0 {
+            self.is_hdma = true;
+            // Started in HBlank or with the LCD off, the first block doesn't
+            // wait for the next HBlank
+            self.gpu.hblank_started = self.gpu.is_hblank();
+        } else {
+            while !self.hdma_block() {}
+        }
+    }
+
+    // Copies the next 16 bytes, returns whether it was the last block
+    fn hdma_block(&mut self) -> bool {
+        for _ in 0..16 {
+            let (src, dst) = (self.hdma_src, self.hdma_dst);
+            let val = self.rb(src);
+            self.gpu.wb_vram(0x8000 | dst, val);
+            self.hdma_src = src.wrapping_add(1);
+            self.hdma_dst = (dst + 1) & 0x1FFF;
+        }
+        // 32 clocks per block at either speed
+        self.hdma_stall += if self.double_speed {64} else {32};
+
+        self.hdma_len = self.hdma_len.wrapping_sub(1) & 0x7F;
+        self.hdma_len == 0x7F
+    }
+
+    // Copies a block if an HBlank DMA is running and HBlank started. Returns
+    // the CPU cycles taken by the copies since the last call.
+    pub fn handle_hdma(&mut self) -> u32 {
+        let hblank = mem::replace(&mut self.gpu.hblank_started, false);
+        if self.is_hdma && hblank && self.hdma_block() {
+            self.is_hdma = false;
+        }
+        mem::replace(&mut self.hdma_stall, 0)
+    }
 }
 
 //  ======================================
@@ -534,4 +690,133 @@ mod mem_tests {
         assert_eq!(mem.read_byte_raw(0x8006), 0x56);
         assert_eq!(mem.read_byte_raw(0x8007), 0x78);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cartridge_access_goes_to_mapper() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0150] = 0x12;
+        rom[0x4000] = 0x34;
+
+        let mut mem = Memory::new();
+        // ROM+RAM, 8KB
+        mem.load_cartridge(rom, 0x08, 0x02).unwrap();
+
+        assert_eq!(mem.rb(0x0150), 0x12);
+        assert_eq!(mem.rb(0x4000), 0x34);
+        // ROM can't be written to
+        mem.wb(0x0150, 0xFF);
+        assert_eq!(mem.rb(0x0150), 0x12);
+
+        mem.wb(0xA010, 0x56);
+        assert_eq!(mem.rb(0xA010), 0x56);
+        assert_eq!(mem.get_mapper().save_battery()[0x10], 0x56);
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut mem = Memory::new();
+
+        // Only bank 1 on the DMG
+        mem.wb(0xFF70, 2);
+        mem.wb(0xD000, 0x11);
+        assert_eq!(mem.rb(0xFF70), 0xFF);
+
+        mem.set_cgb_mode();
+        mem.wb(0xFF70, 2);
+        assert_eq!(mem.rb(0xD000), 0x00);
+        mem.wb(0xD000, 0x22);
+        assert_eq!(mem.rb(0xF000), 0x22);
+
+        // Bank 0 selects bank 1
+        mem.wb(0xFF70, 0);
+        assert_eq!(mem.rb(0xFF70), 0xF8);
+        assert_eq!(mem.rb(0xD000), 0x11);
+        mem.wb(0xFF70, 2);
+        assert_eq!(mem.rb(0xD000), 0x22);
+
+        mem.wb(0xC000, 0x33);
+        assert_eq!(mem.rb(0xE000), 0x33);
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut mem = Memory::new();
+        assert!(!mem.switch_speed());
+
+        mem.set_cgb_mode();
+        assert_eq!(mem.rb(0xFF4D), 0x7E);
+        mem.wb(0xFF4D, 0x01);
+        assert_eq!(mem.rb(0xFF4D), 0x7F);
+        assert!(mem.switch_speed());
+        assert!(mem.double_speed);
+        assert_eq!(mem.rb(0xFF4D), 0xFE);
+        assert!(!mem.switch_speed());
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        let mut mem = Memory::new();
+        mem.set_cgb_mode();
+        for i in 0..0x20 {
+            mem.wb(0xC100 + i, i as u8 + 1);
+        }
+
+        // The low 4 bits of the addresses and the top 3 of the destination
+        // are ignored
+        mem.wb(0xFF51, 0xC1);
+        mem.wb(0xFF52, 0x0F);
+        mem.wb(0xFF53, 0xE8);
+        mem.wb(0xFF54, 0x1F);
+        mem.wb(0xFF55, 0x01);
+        assert_eq!(mem.rb(0x8810), 0x01);
+        assert_eq!(mem.rb(0x882F), 0x20);
+        assert_eq!(mem.rb(0xFF55), 0xFF);
+
+        // The CPU waits for both blocks
+        assert_eq!(mem.handle_hdma(), 64);
+        assert_eq!(mem.handle_hdma(), 0);
+    }
+
+    #[test]
+    fn hblank_dma() {
+        let mut mem = Memory::new();
+        mem.set_cgb_mode();
+        let mut if_ = 0;
+        for i in 0..0x30 {
+            mem.wb(0xC000 + i, 0xAA);
+        }
+        mem.wb(0xFF51, 0xC0);
+        mem.wb(0xFF52, 0x00);
+        mem.wb(0xFF53, 0x00);
+        mem.wb(0xFF54, 0x00);
+
+        // With the LCD off the first block is copied right away
+        mem.wb(0xFF40, 0x00);
+        mem.wb(0xFF55, 0x82);
+        assert_eq!(mem.handle_hdma(), 32);
+        assert_eq!(mem.rb(0xFF55), 0x01);
+        assert_eq!(mem.handle_hdma(), 0);
+
+        // Then one every HBlank
+        mem.wb(0xFF40, 0x91);
+        mem.gpu.step(456, &mut if_);
+        assert_eq!(mem.handle_hdma(), 32);
+        assert_eq!(mem.rb(0xFF55), 0x00);
+        assert_eq!(mem.rb(0x801F), 0xAA);
+
+        // Stopped with a block left
+        mem.wb(0xFF55, 0x00);
+        assert_eq!(mem.rb(0xFF55), 0x80);
+        mem.gpu.step(456, &mut if_);
+        assert_eq!(mem.handle_hdma(), 0);
+        assert_eq!(mem.rb(0x8020), 0x00);
+
+        // Starting again carries on from where it stopped
+        mem.wb(0xFF55, 0x80);
+        mem.gpu.step(456, &mut if_);
+        assert_eq!(mem.handle_hdma(), 32);
+        assert_eq!(mem.rb(0xFF55), 0xFF);
+        assert_eq!(mem.rb(0x802F), 0xAA);
+        assert_eq!(mem.rb(0x8030), 0x00);
+    }
+}
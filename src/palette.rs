@@ -0,0 +1,152 @@
+//
+//      DMG color palettes
+//
+
+use std::io;
+
+pub type Color = [u8; 4];
+pub type Palette = [Color; 4];
+
+// What the DMG's 4 shades look like on screen, from lightest to darkest. The
+// BG and window share a palette, the two sprite palettes (OBP0/OBP1) get their
+// own.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct ColorScheme {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+pub const PALETTE_BW: Palette = [
+    [255, 255, 255, 255],
+    [148, 148, 148, 255],
+    [ 86,  86,  86, 255],
+    [  0,   0,   0, 255],
+];
+pub const PALETTE_GREEN: Palette = [
+    [225, 247, 207, 255],
+    [136, 193, 107, 255],
+    [ 49,  106, 74, 255],
+    [ 7,  24, 31, 255],
+];
+pub const PALETTE_PUKE_GREEN: Palette = [
+    [157, 188, 7, 255],
+    [122, 156, 107, 255],
+    [ 53,  99, 56, 255],
+    [ 13,  58, 8, 255],
+];
+
+// What the CGB shows DMG games it doesn't know in
+const CGB_BG: Palette = [
+    [255, 255, 255, 255],
+    [123, 255,  49, 255],
+    [  0,  99, 197, 255],
+    [  0,   0,   0, 255],
+];
+const CGB_OBJ: Palette = [
+    [255, 255, 255, 255],
+    [255, 132, 132, 255],
+    [148,  58,  58, 255],
+    [  0,   0,   0, 255],
+];
+
+// Built in color schemes, the first one is the default
+pub const SCHEMES: [(&'static str, ColorScheme); 4] = [
+    ("Green", ColorScheme { bg: PALETTE_GREEN, obj0: PALETTE_GREEN, obj1: PALETTE_GREEN }),
+    ("Black and white", ColorScheme { bg: PALETTE_BW, obj0: PALETTE_BW, obj1: PALETTE_BW }),
+    ("Puke green", ColorScheme {
+        bg: PALETTE_PUKE_GREEN, obj0: PALETTE_PUKE_GREEN, obj1: PALETTE_PUKE_GREEN
+    }),
+    ("CGB", ColorScheme { bg: CGB_BG, obj0: CGB_OBJ, obj1: CGB_OBJ }),
+];
+
+impl ColorScheme {
+    // Parses a palette file. Each line is a layer (bg, obj0 or obj1) followed
+    // by its 4 colors as RRGGBB hex, lightest first. Layers that are left out
+    // use the BG's colors. # starts a comment.
+    //
+    //      bg   FFFFFF 7BFF31 0063C5 000000
+    //      obj0 FFFFFF FF8484 943A3A 000000
+    pub fn parse(text: &str) -> io::Result<ColorScheme> {
+        let (mut bg, mut obj0, mut obj1) = (None, None, None);
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap();
+            let mut words = line.split_whitespace();
+            let layer = match words.next() {
+                Some("bg") => &mut bg,
+                Some("obj0") => &mut obj0,
+                Some("obj1") => &mut obj1,
+                Some(word) => return Err(parse_error(n, &format!("Unknown layer '{}'", word))),
+                None => continue,
+            };
+
+            let colors: Vec<&str> = words.collect();
+            if colors.len() != 4 {
+                return Err(parse_error(n, "Expected 4 colors"));
+            }
+            let mut pal = [[0; 4]; 4];
+            for (color, word) in pal.iter_mut().zip(colors) {
+                *color = match parse_color(word) {
+                    Some(color) => color,
+                    None => return Err(parse_error(n, &format!("Invalid color '{}'", word))),
+                };
+            }
+            *layer = Some(pal);
+        }
+
+        let bg = match bg {
+            Some(bg) => bg,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Palette has no bg colors")),
+        };
+        Ok(ColorScheme {
+            bg: bg,
+            obj0: obj0.unwrap_or(bg),
+            obj1: obj1.unwrap_or(bg),
+        })
+    }
+}
+
+impl Default for ColorScheme {
+    fn default() -> ColorScheme {
+        SCHEMES[0].1
+    }
+}
+
+fn parse_color(word: &str) -> Option<Color> {
+    if word.len() != 6 { return None }
+    match u32::from_str_radix(word, 16) {
+        Ok(rgb) => Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255]),
+        Err(_) => None,
+    }
+}
+
+fn parse_error(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Palette line {}: {}", line + 1, msg))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod palette_tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let scheme = ColorScheme::parse("
+            # CGB colors
+            bg   FFFFFF 7BFF31 0063C5 000000
+            obj1 FFFFFF FF8484 943a3a 000000    # lowercase is fine too
+        ").unwrap();
+        assert_eq!(scheme.bg, CGB_BG);
+        assert_eq!(scheme.obj0, CGB_BG);
+        assert_eq!(scheme.obj1, CGB_OBJ);
+
+        assert!(ColorScheme::parse("obj0 FFFFFF FFFFFF FFFFFF 000000").is_err());
+        assert!(ColorScheme::parse("bg FFFFFF FFFFFF 000000").is_err());
+        assert!(ColorScheme::parse("bg FFFFFF FFFFFF FFFFFF 00000G").is_err());
+        assert!(ColorScheme::parse("win FFFFFF FFFFFF FFFFFF 000000").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//
+//      MBC3 Real Time Clock
+//
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// RTC register numbers, as selected by writing to 4000-5FFF
+pub const RTC_S: u8  = 0x08;  // Seconds   0-59
+pub const RTC_M: u8  = 0x09;  // Minutes   0-59
+pub const RTC_H: u8  = 0x0A;  // Hours     0-23
+pub const RTC_DL: u8 = 0x0B;  // Lower 8 bits of Day Counter
+pub const RTC_DH: u8 = 0x0C;  // Upper 1 bit of Day Counter, Carry Bit, Halt Flag
+
+const DAY_MAX: u64 = 0x1FF;
+
+// Source of the current time in seconds. The RTC only looks at the difference
+// between calls, so the epoch doesn't matter. Tests can plug in their own to be
+// deterministic.
+pub trait RtcClock {
+    fn now(&self) -> u64;
+}
+
+// Wall clock time
+pub struct SystemClock;
+
+impl RtcClock for SystemClock {
+    fn now(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(time) => time.as_secs(),
+            Err(_) => 0,
+        }
+    }
+}
+
+// Size of the RTC data appended to .sav files, in the format used by BGB and
+// VBA-M: live and latched registers as 32-bit values, then a 64-bit timestamp
+pub const RTC_SAVE_SIZE: usize = 48;
+// Same, with a 32-bit timestamp
+const RTC_SAVE_SIZE_SHORT: usize = 44;
+
+// The clock's counters
+#[derive(Default, Copy, Clone)]
+struct Counter {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,   // Day counter overflowed
+}
+
+impl Counter {
+    // Counter values in register format
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            ((self.days >> 8) as u8 & 1) | ((self.halt as u8) << 6) | ((self.carry as u8) << 7),
+        ]
+    }
+
+    fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            RTC_S => self.seconds = data & 0x3F,
+            RTC_M => self.minutes = data & 0x3F,
+            RTC_H => self.hours = data & 0x1F,
+            RTC_DL => self.days = (self.days & 0x100) | data as u16,
+            RTC_DH => {
+                self.days = (self.days & 0xFF) | ((data as u16 & 1) << 8);
+                self.halt = data & 0x40 != 0;
+                self.carry = data & 0x80 != 0;
+            },
+            _ => {}
+        }
+    }
+
+    fn advance(&mut self, secs: u64) {
+        let total = secs + self.seconds as u64 +
+            60 * (self.minutes as u64 + 60 * (self.hours as u64 + 24 * self.days as u64));
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+
+        let days = total / (3600 * 24);
+        if days > DAY_MAX {
+            self.carry = true;
+        }
+        self.days = (days & DAY_MAX) as u16;
+    }
+}
+
+pub struct Rtc {
+    clock: Box<RtcClock>,
+
+    // Live counters
+    counter: Counter,
+
+    // Values visible to the CPU, copied from the counters on a latch
+    latched: [u8; 5],
+    // True if 00h was the last value written to 6000-7FFF
+    latch_armed: bool,
+
+    // Clock time the counters were last brought up to date at
+    last_time: u64,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc::with_clock(Box::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Box<RtcClock>) -> Rtc {
+        let now = clock.now();
+        Rtc {
+            clock: clock,
+            counter: Default::default(),
+            latched: [0; 5],
+            latch_armed: false,
+            last_time: now,
+        }
+    }
+
+    // Read a latched register
+    pub fn rb(&self, reg: u8) -> u8 {
+        match reg {
+            RTC_S ... RTC_DH => self.latched[(reg - RTC_S) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    // Write a register. Writes go straight to the counters.
+    pub fn wb(&mut self, reg: u8, data: u8) {
+        self.update();
+        self.counter.write(reg, data);
+    }
+
+    // Handles writes to 6000-7FFF. Writing 00h and then 01h latches the
+    // current time into the registers.
+    pub fn latch(&mut self, data: u8) {
+        if self.latch_armed && data == 1 {
+            self.update();
+            self.latched = self.counter.registers();
+        }
+        self.latch_armed = data == 0;
+    }
+
+    // Serializes the clock for the end of a .sav file
+    pub fn save(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+
+        for &reg in self.current(now).registers().iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&[reg, 0, 0, 0]);
+        }
+        for i in 0..8 {
+            data.push((now >> (i * 8)) as u8);
+        }
+        data
+    }
+
+    // Restores the clock from save() data. The time that passed since it was
+    // saved gets added to the counters.
+    pub fn load(&mut self, data: &[u8]) {
+        if data.len() != RTC_SAVE_SIZE && data.len() != RTC_SAVE_SIZE_SHORT {
+            warn!("Invalid RTC save data size: {}", data.len());
+            return;
+        }
+
+        let mut counter: Counter = Default::default();
+        for i in 0..5 {
+            counter.write(RTC_S + i as u8, data[i * 4]);
+            self.latched[i] = data[20 + i * 4];
+        }
+        self.counter = counter;
+
+        let timestamp = data[40..].iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        self.last_time = timestamp;
+        self.update();
+    }
+
+    // The counters as they'd be at the given time
+    fn current(&self, now: u64) -> Counter {
+        let mut counter = self.counter;
+        if !counter.halt && now > self.last_time {
+            counter.advance(now - self.last_time);
+        }
+        counter
+    }
+
+    // Advance the counters by the time passed since the last update
+    fn update(&mut self) {
+        let now = self.clock.now();
+        self.counter = self.current(now);
+        self.last_time = now;
+    }
+}
+
+impl fmt::Debug for Rtc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " day: {}\n time: {:02}:{:02}:{:02}\n halt: {}\n carry: {}",
+            self.counter.days,
+            self.counter.hours,
+            self.counter.minutes,
+            self.counter.seconds,
+            self.counter.halt,
+            self.counter.carry,
+            )
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+// Clock reading a time the test sets through the shared cell
+#[cfg(test)]
+pub struct FakeClock(pub ::std::rc::Rc<::std::cell::Cell<u64>>);
+
+#[cfg(test)]
+impl RtcClock for FakeClock {
+    fn now(&self) -> u64 { self.0.get() }
+}
+
+#[cfg(test)]
+mod rtc_tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    fn fake_rtc() -> (Rtc, Rc<Cell<u64>>) {
+        let time = Rc::new(Cell::new(1000));
+        (Rtc::with_clock(Box::new(FakeClock(time.clone()))), time)
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.latch(0);
+        rtc.latch(1);
+    }
+
+    #[test]
+    fn counts_and_latches() {
+        let (mut rtc, time) = fake_rtc();
+
+        time.set(1000 + 3 * 86400 + 5 * 3600 + 7 * 60 + 9);
+        // Not latched yet
+        assert_eq!(rtc.rb(RTC_S), 0);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.rb(RTC_S), 9);
+        assert_eq!(rtc.rb(RTC_M), 7);
+        assert_eq!(rtc.rb(RTC_H), 5);
+        assert_eq!(rtc.rb(RTC_DL), 3);
+        assert_eq!(rtc.rb(RTC_DH), 0);
+
+        // Latched values stay until the next latch
+        time.set(time.get() + 1);
+        assert_eq!(rtc.rb(RTC_S), 9);
+        // 01h without a preceding 00h doesn't latch
+        rtc.latch(1);
+        assert_eq!(rtc.rb(RTC_S), 9);
+        latch(&mut rtc);
+        assert_eq!(rtc.rb(RTC_S), 10);
+    }
+
+    #[test]
+    fn halt_stops_counting() {
+        let (mut rtc, time) = fake_rtc();
+
+        rtc.wb(RTC_DH, 0x40);
+        time.set(time.get() + 100);
+        latch(&mut rtc);
+        assert_eq!(rtc.rb(RTC_S), 0);
+        assert_eq!(rtc.rb(RTC_DH), 0x40);
+
+        rtc.wb(RTC_DH, 0x00);
+        time.set(time.get() + 100);
+        latch(&mut rtc);
+        assert_eq!(rtc.rb(RTC_M), 1);
+        assert_eq!(rtc.rb(RTC_S), 40);
+    }
+
+    #[test]
+    fn day_counter_carry() {
+        let (mut rtc, time) = fake_rtc();
+
+        rtc.wb(RTC_DL, 0xFF);
+        rtc.wb(RTC_DH, 0x01);
+        rtc.wb(RTC_H, 23);
+        rtc.wb(RTC_M, 59);
+        rtc.wb(RTC_S, 59);
+        time.set(time.get() + 1);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.rb(RTC_DL), 0);
+        assert_eq!(rtc.rb(RTC_DH), 0x80);
+        assert_eq!(rtc.rb(RTC_H), 0);
+
+        // Carry stays set until cleared
+        rtc.wb(RTC_DH, 0x00);
+        latch(&mut rtc);
+        assert_eq!(rtc.rb(RTC_DH), 0x00);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (mut rtc, time) = fake_rtc();
+
+        rtc.wb(RTC_H, 10);
+        time.set(time.get() + 30);
+        latch(&mut rtc);
+
+        let data = rtc.save();
+        assert_eq!(data.len(), RTC_SAVE_SIZE);
+        assert_eq!(data[(RTC_H - RTC_S) as usize * 4], 10);
+
+        // Two minutes pass while the emulator isn't running
+        time.set(time.get() + 120);
+        let (mut loaded, loaded_time) = fake_rtc();
+        loaded_time.set(time.get());
+        loaded.load(&data);
+
+        // Latched registers are restored as they were
+        assert_eq!(loaded.rb(RTC_S), 30);
+        assert_eq!(loaded.rb(RTC_H), 10);
+        latch(&mut loaded);
+        assert_eq!(loaded.rb(RTC_S), 30);
+        assert_eq!(loaded.rb(RTC_M), 2);
+        assert_eq!(loaded.rb(RTC_H), 10);
+    }
+}
@@ -0,0 +1,190 @@
+//
+//      Save states
+//
+
+/*
+  A save state is the whole machine written out field by field, little endian:
+
+    "RBST"            magic
+    u32               format version, bumped whenever the layout changes
+    u16               global checksum of the ROM the state was made with
+    ...               Cpu, then Memory (which writes the Timer, Apu, Gpu and Mapper)
+
+  Nothing is tagged, components read back exactly what they wrote and in the
+  same order.
+*/
+
+use std::io;
+
+pub const STATE_MAGIC: &'static [u8; 4] = b"RBST";
+pub const STATE_VERSION: u32 = 10;
+
+// Error for save states that can't be loaded
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&[val as u8, (val >> 8) as u8]);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.u16(val as u16);
+        self.u16((val >> 16) as u16);
+    }
+
+    // Fixed size data, the reader has to know the length
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn vec(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data: data, pos: 0 }
+    }
+
+    // Whether everything was read
+    pub fn is_done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid_data("Save state ends unexpectedly"));
+        }
+        let data = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(try!(self.u8()) != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let data = try!(self.take(2));
+        Ok(data[0] as u16 | (data[1] as u16) << 8)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let low = try!(self.u16()) as u32;
+        let high = try!(self.u16()) as u32;
+        Ok(low | high << 16)
+    }
+
+    // Fills the buffer, counterpart of StateWriter::bytes()
+    pub fn bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len();
+        buf.copy_from_slice(try!(self.take(len)));
+        Ok(())
+    }
+
+    // Counterpart of StateWriter::vec()
+    pub fn vec(&mut self) -> io::Result<Vec<u8>> {
+        let len = try!(self.u32()) as usize;
+        Ok(try!(self.take(len)).to_vec())
+    }
+
+    // Reads data written with StateWriter::vec() into a buffer of the same size
+    pub fn vec_into(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let len = try!(self.u32()) as usize;
+        if len != buf.len() {
+            return Err(invalid_data("Save state doesn't match the cartridge's memory size"));
+        }
+        self.bytes(buf)
+    }
+
+    // Checks the magic and the format version
+    pub fn header(&mut self) -> io::Result<()> {
+        let mut magic = [0u8; 4];
+        try!(self.bytes(&mut magic));
+        if &magic != STATE_MAGIC {
+            return Err(invalid_data("Not a save state"));
+        }
+        let version = try!(self.u32());
+        if version != STATE_VERSION {
+            return Err(invalid_data(&format!("Unsupported save state version: {}", version)));
+        }
+        Ok(())
+    }
+}
+
+// Writes the magic and the format version
+pub fn write_header(w: &mut StateWriter) {
+    w.bytes(STATE_MAGIC);
+    w.u32(STATE_VERSION);
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new();
+        write_header(&mut w);
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789ABCDE);
+        w.vec(&[1, 2, 3]);
+        let data = w.into_inner();
+
+        let mut r = StateReader::new(&data);
+        r.header().unwrap();
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert_eq!(r.bool().unwrap(), true);
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u32().unwrap(), 0x789ABCDE);
+        assert_eq!(r.vec().unwrap(), vec![1, 2, 3]);
+        assert!(r.is_done());
+
+        // Truncated
+        let mut r = StateReader::new(&data[..data.len() - 1]);
+        r.header().unwrap();
+        r.bytes(&mut [0u8; 8]).unwrap();
+        assert!(r.vec().is_err());
+        assert!(StateReader::new(&data[..6]).header().is_err());
+    }
+}
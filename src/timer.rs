@@ -1,6 +1,7 @@
 
 use cpu::Interrupt;
-use std::fmt;
+use state::{StateWriter, StateReader};
+use std::{fmt, io};
 
 #[allow(dead_code)]
 #[allow(unused_variables)]
@@ -8,8 +9,9 @@ use std::fmt;
 const DIV_AFTER_BIOS: u16 = 0xABCC;
 
 pub struct Timer {
-    // This register is incremented at rate of 16384Hz
-    // Writing any value to this register resets it to 00h
+    // Internal 16 bit counter, incremented every clock. DIV (0xff04) is its
+    // upper 8 bits, so it goes up at 16384Hz.
+    // Writing any value to DIV resets the whole counter
     pub div: u16,
     // This timer is incremented by a clock frequency specified by the TAC register ($FF07)
     // When the value overflows (gets bigger than FFh) then it will be reset to the
@@ -19,7 +21,13 @@ pub struct Timer {
     pub tma: u8,
     pub tac: u8,
 
-    tima_speed: u32,
+    // TIMA reads 0 for 4 clocks after overflowing, TMA is only loaded and the
+    // interrupt requested after that. Writing TIMA in the meantime cancels
+    // both.
+    reload_delay: u8,
+    // The 4 clocks TMA is being loaded in. TIMA writes are ignored and TMA
+    // writes go through to TIMA.
+    reload_cycles: u8,
 }
 
 impl Timer {
@@ -29,52 +37,245 @@ impl Timer {
             tima: 0,
             tma: 0,
             tac: 0,
-            tima_speed: 256
+            reload_delay: 0,
+            reload_cycles: 0,
         }
     }
 
-    pub fn update(&mut self) {
-        // See step() function for timings
+    // The counter bit TAC picks. TIMA goes up when it goes from 1 to 0:
+    //      0: 4096Hz, bit 9
+    //      1: 262144Hz, bit 3
+    //      2: 65536Hz, bit 5
+    //      3: 16384Hz, bit 7
+    fn tima_bit(&self) -> u16 {
         match self.tac & 0x3 {
-            0x0 => { self.tima_speed = 256; }
-            0x1 => { self.tima_speed = 4; }
-            0x2 => { self.tima_speed = 16; }
-            0x3 => { self.tima_speed = 64; }
-            _ => {}
+            0x0 => 1 << 9,
+            0x1 => 1 << 3,
+            0x2 => 1 << 5,
+            _ => 1 << 7,
         }
     }
 
+    // What TIMA is clocked by, the selected bit ANDed with the enable bit.
+    // Anything that makes it fall increments TIMA, not just the counter
+    // ticking, which is why writing DIV or TAC can increment it too.
+    fn signal(&self) -> bool {
+        self.tac & 0b100 != 0 && self.div & self.tima_bit() != 0
+    }
+
     pub fn step(&mut self, ticks: u32, if_: &mut u8) {
-        self.div = self.div.wrapping_add(1);
-        if (self.tac & 0b100) != 0 {
-            // Check for 8-bit overflow
-            if ((self.tima as u16) + 1) & 0xFF == 0 {
-                self.tima = self.tma;
-
-                // Fire Timer interrupt
-                *if_ |= Interrupt::Timer as u8;
-            } else {
-                // Increment TIMA
-                self.tima += 1;
+        for _ in 0..ticks {
+            if self.reload_cycles > 0 {
+                self.reload_cycles -= 1;
+            }
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
+                    self.tima = self.tma;
+                    self.reload_cycles = 4;
+
+                    // Fire Timer interrupt
+                    *if_ |= Interrupt::Timer as u8;
+                }
             }
+
+            let signal = self.signal();
+            self.div = self.div.wrapping_add(1);
+            self.check_falling_edge(signal, if_);
+        }
+    }
+
+    // 0xff04 write
+    pub fn reset_div(&mut self, if_: &mut u8) {
+        let signal = self.signal();
+        self.div = 0;
+        self.check_falling_edge(signal, if_);
+    }
+
+    // 0xff05 write
+    pub fn write_tima(&mut self, val: u8) {
+        if self.reload_cycles > 0 { return }
+
+        self.tima = val;
+        self.reload_delay = 0;
+    }
+
+    // 0xff06 write
+    pub fn write_tma(&mut self, val: u8) {
+        self.tma = val;
+        if self.reload_cycles > 0 {
+            self.tima = val;
+        }
+    }
+
+    // 0xff07 write
+    pub fn set_tac(&mut self, val: u8, if_: &mut u8) {
+        let signal = self.signal();
+        self.tac = val & 0b111;
+        self.check_falling_edge(signal, if_);
+    }
+
+    fn check_falling_edge(&mut self, signal: bool, if_: &mut u8) {
+        if signal && !self.signal() {
+            self.increment_tima(if_);
+        }
+    }
+
+    fn increment_tima(&mut self, if_: &mut u8) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload_delay = 4;
         }
     }
 
     pub fn reset_bios_skip(&mut self) {
         self.div = DIV_AFTER_BIOS
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.div);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u8(self.reload_delay);
+        w.u8(self.reload_cycles);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.div = try!(r.u16());
+        self.tima = try!(r.u8());
+        self.tma = try!(r.u8());
+        self.tac = try!(r.u8());
+        self.reload_delay = try!(r.u8());
+        self.reload_cycles = try!(r.u8());
+        Ok(())
+    }
 }
 
 
 impl fmt::Debug for Timer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, " div: {}\n tima: {}\n tma: {}\n tac: {}\n tima_speed: {}
+        write!(f, " div: {}\n tima: {}\n tma: {}\n tac: {}
             ",
-            self.div,
+            self.div >> 8,
             self.tima,
             self.tma,
             self.tac,
-            self.tima_speed,
             )
     }
-}
\ No newline at end of file
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod timer_tests {
+    use super::*;
+
+    #[test]
+    fn tima_rate() {
+        let mut timer = Timer::new();
+        let mut if_ = 0;
+        timer.div = 0;
+        timer.set_tac(0b101, &mut if_);
+
+        // Every 16 clocks, whatever the step size
+        timer.step(15, &mut if_);
+        assert_eq!(timer.tima, 0);
+        timer.step(1, &mut if_);
+        assert_eq!(timer.tima, 1);
+        for _ in 0..40 {
+            timer.step(4, &mut if_);
+        }
+        assert_eq!(timer.tima, 11);
+        assert_eq!(timer.div, 176);
+
+        // Disabled
+        timer.set_tac(0b001, &mut if_);
+        timer.step(64, &mut if_);
+        assert_eq!(timer.tima, 11);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut timer = Timer::new();
+        let mut if_ = 0;
+        timer.div = 0;
+        timer.tima = 0xFF;
+        timer.tma = 0x80;
+        timer.set_tac(0b101, &mut if_);
+
+        // 0 for 4 clocks, then TMA and the interrupt
+        timer.step(16, &mut if_);
+        assert_eq!(timer.tima, 0x00);
+        assert_eq!(if_, 0);
+        timer.step(3, &mut if_);
+        assert_eq!(timer.tima, 0x00);
+        timer.step(1, &mut if_);
+        assert_eq!(timer.tima, 0x80);
+        assert_eq!(if_, Interrupt::Timer as u8);
+    }
+
+    #[test]
+    fn writes_around_reload() {
+        let mut timer = Timer::new();
+        let mut if_ = 0;
+        timer.div = 0;
+        timer.tma = 0x80;
+        timer.set_tac(0b101, &mut if_);
+
+        // Writing TIMA before the reload cancels it
+        timer.tima = 0xFF;
+        timer.step(18, &mut if_);
+        timer.write_tima(0x10);
+        timer.step(4, &mut if_);
+        assert_eq!(timer.tima, 0x10);
+        assert_eq!(if_, 0);
+
+        // Writing TIMA while reloading is ignored, TMA goes through. The
+        // counter is at 22, the next overflow at 32.
+        timer.tima = 0xFF;
+        timer.step(14, &mut if_);
+        assert_eq!(timer.tima, 0x80);
+        timer.write_tima(0x10);
+        assert_eq!(timer.tima, 0x80);
+        timer.write_tma(0x40);
+        assert_eq!(timer.tima, 0x40);
+
+        // Not anymore once it's done
+        timer.step(4, &mut if_);
+        timer.write_tma(0x50);
+        assert_eq!(timer.tima, 0x40);
+        timer.write_tima(0x10);
+        assert_eq!(timer.tima, 0x10);
+    }
+
+    #[test]
+    fn div_and_tac_glitches() {
+        let mut timer = Timer::new();
+        let mut if_ = 0;
+        timer.div = 0;
+        timer.set_tac(0b101, &mut if_);
+
+        // Resetting DIV with the selected bit set is a falling edge
+        timer.step(8, &mut if_);
+        timer.reset_div(&mut if_);
+        assert_eq!(timer.tima, 1);
+        // Not with it clear
+        timer.step(7, &mut if_);
+        timer.reset_div(&mut if_);
+        assert_eq!(timer.tima, 1);
+
+        // Disabling the timer with the bit set
+        timer.step(8, &mut if_);
+        timer.set_tac(0b001, &mut if_);
+        assert_eq!(timer.tima, 2);
+
+        // Going from a set bit (3) to a clear one (9)
+        timer.set_tac(0b101, &mut if_);
+        timer.set_tac(0b100, &mut if_);
+        assert_eq!(timer.tima, 3);
+    }
+}
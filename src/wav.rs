@@ -0,0 +1,200 @@
+//
+//      WAV audio recording
+//
+
+use std::io::{self, Write, Seek, SeekFrom};
+
+use apu::CLOCK_SPEED;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Writes 16-bit stereo PCM to a .wav file. The sizes in the header are filled
+// in by finish(), or when it's dropped.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_len: u32,  // bytes
+    is_finished: bool,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        try!(out.write_all(b"RIFF"));
+        try!(write_u32(&mut out, HEADER_SIZE - 8));
+        try!(out.write_all(b"WAVE"));
+
+        try!(out.write_all(b"fmt "));
+        try!(write_u32(&mut out, 16));
+        try!(write_u16(&mut out, 1));   // PCM
+        try!(write_u16(&mut out, CHANNELS));
+        try!(write_u32(&mut out, sample_rate));
+        try!(write_u32(&mut out, sample_rate * block_align as u32));
+        try!(write_u16(&mut out, block_align));
+        try!(write_u16(&mut out, BITS_PER_SAMPLE));
+
+        try!(out.write_all(b"data"));
+        try!(write_u32(&mut out, 0));
+
+        Ok(WavWriter { out: out, data_len: 0, is_finished: false })
+    }
+
+    // Interleaved stereo samples, left first
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            data.push(sample as u8);
+            data.push((sample >> 8) as u8);
+        }
+        try!(self.out.write_all(&data));
+        self.data_len += data.len() as u32;
+        Ok(())
+    }
+
+    // Fills in the header sizes
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.is_finished { return Ok(()) }
+        self.is_finished = true;
+
+        try!(self.out.seek(SeekFrom::Start(4)));
+        try!(write_u32(&mut self.out, HEADER_SIZE - 8 + self.data_len));
+        try!(self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4)));
+        try!(write_u32(&mut self.out, self.data_len));
+        try!(self.out.seek(SeekFrom::End(0)));
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(why) = self.finish() {
+            error!("Couldn't finish WAV file: {}", why);
+        }
+    }
+}
+
+// Records APU output, locked to the emulator's clock count: however many
+// samples the APU hands over, the file gets exactly as many as the emulated
+// time since the recording started is worth. Recordings of the same input are
+// identical.
+pub struct WavRecorder<W: Write + Seek> {
+    writer: WavWriter<W>,
+    sample_rate: u32,
+
+    // Emulator::clock_cycles at the last record(). That counts at normal
+    // speed, Cpu::total_cycles runs twice as fast in double speed mode and
+    // would speed the recording up.
+    last_cycles: u32,
+    elapsed_cycles: u64,
+    written: u64,           // stereo samples
+    last_sample: [i16; 2],
+}
+
+impl<W: Write + Seek> WavRecorder<W> {
+    pub fn new(out: W, sample_rate: u32, clock_cycles: u32) -> io::Result<WavRecorder<W>> {
+        Ok(WavRecorder {
+            writer: try!(WavWriter::new(out, sample_rate)),
+            sample_rate: sample_rate,
+            last_cycles: clock_cycles,
+            elapsed_cycles: 0,
+            written: 0,
+            last_sample: [0; 2],
+        })
+    }
+
+    // Writes the samples produced since the last call. Missing samples repeat
+    // the last one, extra ones are dropped. `clock_cycles` is the emulator's
+    // normal speed clock count (Emulator::clock_cycles).
+    pub fn record(&mut self, samples: &[i16], clock_cycles: u32) -> io::Result<()> {
+        self.elapsed_cycles += clock_cycles.wrapping_sub(self.last_cycles) as u64;
+        self.last_cycles = clock_cycles;
+
+        let expected = self.elapsed_cycles * self.sample_rate as u64 / CLOCK_SPEED as u64;
+        let count = (expected - self.written) as usize;
+
+        let used = count.min(samples.len() / 2);
+        if used > 0 {
+            try!(self.writer.write_samples(&samples[..used * 2]));
+            self.last_sample = [samples[used * 2 - 2], samples[used * 2 - 1]];
+        }
+        if used < count {
+            let mut padding = Vec::with_capacity((count - used) * 2);
+            for _ in used..count {
+                padding.extend_from_slice(&self.last_sample);
+            }
+            try!(self.writer.write_samples(&padding));
+        }
+        self.written = expected;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.finish()
+    }
+
+    pub fn get_writer(&self) -> &WavWriter<W> {
+        &self.writer
+    }
+}
+
+fn write_u16<W: Write>(out: &mut W, val: u16) -> io::Result<()> {
+    out.write_all(&[val as u8, (val >> 8) as u8])
+}
+
+fn write_u32<W: Write>(out: &mut W, val: u32) -> io::Result<()> {
+    try!(write_u16(out, val as u16));
+    write_u16(out, (val >> 16) as u16)
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod wav_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        data[offset..offset + 4].iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32)
+    }
+
+    #[test]
+    fn header_and_data() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write_samples(&[1, -1, 0x1234, 0]).unwrap();
+        wav.finish().unwrap();
+
+        let data = wav.get_ref().get_ref();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(read_u32(data, 4), 36 + 8);
+        assert_eq!(read_u32(data, 24), 44100);
+        assert_eq!(read_u32(data, 28), 44100 * 4);
+        assert_eq!(read_u32(data, 40), 8);
+        assert_eq!(&data[44..], &[1, 0, 0xFF, 0xFF, 0x34, 0x12, 0, 0]);
+    }
+
+    #[test]
+    fn recording_follows_cycles() {
+        // 1 sample every 4096 cycles
+        let rate = CLOCK_SPEED / 4096;
+        let mut rec = WavRecorder::new(Cursor::new(Vec::new()), rate, 1000).unwrap();
+
+        // Too few samples get padded with the last one
+        rec.record(&[5, 6], 1000 + 4096 * 3).unwrap();
+        // Too many get dropped
+        rec.record(&[7, 8, 9, 9, 9, 9], 1000 + 4096 * 4).unwrap();
+        rec.finish().unwrap();
+
+        let data = rec.get_writer().get_ref().get_ref();
+        assert_eq!(read_u32(data, 40), 4 * 4);
+        assert_eq!(&data[44..], &[5, 0, 6, 0, 5, 0, 6, 0, 5, 0, 6, 0, 7, 0, 8, 0]);
+    }
+}